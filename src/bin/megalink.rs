@@ -1,8 +1,22 @@
-use std::path::PathBuf;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::net::TcpListener;
+use std::time::Duration;
 use clap::Clap;
-use log::{info, warn};
+use log::{info, warn, Level, LevelFilter};
 use anyhow::anyhow;
-use megalink_rs::{EverdriveSerial, Mode, SerialFactory, ResetMode};
+use megalink_rs::rom::{self, Header};
+use megalink_rs::capture::{parse_capture, Capture, Event, Replay, ReplayFactory};
+use megalink_rs::decode::{decode_capture, Decoder};
+use megalink_rs::fault::{FaultInjector, Faults, ModeChangeFault};
+#[cfg(unix)]
+use megalink_rs::pty::Pty;
+use megalink_rs::romdb::RomDb;
+use megalink_rs::sim::{Simulator, SimulatorFactory};
+use megalink_rs::serve::{forward, log_frames, ForwardEnd, Server};
+use megalink_rs::{CancelToken, Error, Progress, ProgressEvent, StatusCode, TcpFactory};
+use megalink_rs::{EverdriveSerial, Mode, ReconnectPolicy, SerialFactory, ResetMode, SaveSync, GameInfo, FpgaSource, System, Transport};
+#[cfg(feature = "serial")]
 use serialport::SerialPort;
 
 #[derive(Clap)]
@@ -10,16 +24,85 @@ struct Opts {
     #[clap(short, long)]
     serial_port: Option<String>,
 
+    /// Don't show progress bars.
+    #[clap(short, long)]
+    quiet: bool,
+
+    /// Show more log messages. Repeat for more detail.
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u64,
+
+    /// Read settings from this file, rather than ~/.config/megalink/config.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Milliseconds to wait for the response to a command.
+    #[clap(long)]
+    command_timeout: Option<u64>,
+
+    /// Milliseconds to wait for stray data when draining the serial port.
+    #[clap(long)]
+    drain_timeout: Option<u64>,
+
+    /// Milliseconds to wait for firmware recovery to finish.
+    #[clap(long)]
+    recovery_timeout: Option<u64>,
+
+    /// Milliseconds to wait for the device to come back after changing mode.
+    #[clap(long)]
+    reconnect_timeout: Option<u64>,
+
+    /// How many times to retry commands after losing sync with the device.
+    #[clap(long)]
+    retries: Option<usize>,
+
+    /// Don't check that the device responds when connecting.
+    #[clap(long)]
+    no_probe: bool,
+
+    /// Talk to a simulated device rather than a serial port. Its flash is
+    /// kept in flash.bin in this directory, and the sd directory is its SD
+    /// card.
+    #[clap(long)]
+    simulate: Option<PathBuf>,
+
+    /// Record everything sent to and from the device to this file.
+    #[clap(long)]
+    capture: Option<PathBuf>,
+
+    /// Play back a capture file rather than talking to a device, and fail
+    /// if the commands sent differ from it.
+    #[clap(long)]
+    replay: Option<PathBuf>,
+
+    /// Inject faults into the connection, for testing. This is a list of
+    /// settings such as `drop=0.001,timeout-every=50,mode-change=lose`.
+    #[clap(long)]
+    faults: Option<String>,
+
     #[clap(subcommand)]
     command: Command,
 }
 
 #[derive(Clap)]
 enum Command {
+    #[clap(flatten)]
+    Device(DeviceCommand),
+    RomInfo(CmdRomInfo),
+    Decode(CmdDecode),
+    Proxy(CmdProxy),
+    Serve(CmdServe),
+}
+
+// Commands which talk to the device through `EverdriveSerial`. This is a
+// plain comment, since clap would show a doc comment as the program's
+// description.
+#[derive(Clap)]
+enum DeviceCommand {
     SetMode(CmdSetMode),
     Reset(CmdReset),
     Recover(CmdRecover),
-    Run(CmdRunGame),
+    Run(Box<CmdRunGame>),
     LoadFPGA(CmdLoadFPGA),
 }
 
@@ -39,19 +122,101 @@ struct CmdRecover;
 
 #[derive(Clap)]
 struct CmdRunGame {
-    path: PathBuf,
+    #[clap(required_unless_present = "sd", conflicts_with = "sd")]
+    path: Option<PathBuf>,
+
+    /// The size of the ROM in bytes, if it is read from a pipe.
+    #[clap(long)]
+    size: Option<u64>,
+
+    /// Boot a ROM stored on the SD card, rather than uploading one.
+    #[clap(long)]
+    sd: Option<String>,
 
     #[clap(short, long)]
     skip_fpga: bool,
 
     #[clap(short, long)]
     fpga: Option<PathBuf>,
+
+    /// Load the FPGA core from this path on the SD card.
+    #[clap(long)]
+    fpga_sd: Option<String>,
+
+    /// Load the FPGA core from this address in flash.
+    #[clap(long)]
+    fpga_flash: Option<u32>,
+
+    /// The system the game is for (md, 32x, sms or gg). Detected from the
+    /// ROM if not given.
+    #[clap(long)]
+    system: Option<String>,
+
+    /// Read the game back after uploading it, to check it.
+    #[clap(long)]
+    verify: bool,
+
+    /// Load this No-Intro DAT file to identify the game.
+    #[clap(long)]
+    dat: Option<PathBuf>,
+
+    /// Report the game to the menu as this SD card path, so that it saves
+    /// to the same files as the copy on the SD card.
+    #[clap(long)]
+    sd_path: Option<String>,
+
+    /// Keep game saves in this directory, and restore them when the same
+    /// ROM is uploaded again.
+    #[clap(long)]
+    save_dir: Option<PathBuf>,
+
+    /// The number of bytes of SRAM to keep.
+    #[clap(long)]
+    sram_size: Option<usize>,
+
+    /// Store the save of the previous game, but don't restore or manage
+    /// the save for this game.
+    #[clap(long)]
+    no_restore: bool,
+}
+
+#[derive(Clap)]
+struct CmdRomInfo {
+    path: PathBuf,
+}
+
+#[derive(Clap)]
+struct CmdDecode {
+    /// A capture written with --capture.
+    path: PathBuf,
+}
+
+#[derive(Clap)]
+struct CmdServe {
+    /// The address to listen on, such as 0.0.0.0:5555.
+    addr: String,
+
+    /// How long in milliseconds the device stays reserved for a client
+    /// after it goes away, so the client can reconnect after changing mode.
+    #[clap(long, default_value = "10000")]
+    hold: u64,
+}
+
+#[derive(Clap)]
+struct CmdProxy {
+    /// Create a symlink to the pseudo-terminal at this path.
+    #[clap(long)]
+    link: Option<PathBuf>,
 }
 
 #[derive(Clap)]
 struct CmdLoadFPGA {
     path: Option<PathBuf>,
 
+    /// The size of the core image in bytes, if it is read from a pipe.
+    #[clap(long)]
+    size: Option<u64>,
+
     #[clap(short, long)]
     sd: Option<String>,
 
@@ -59,13 +224,169 @@ struct CmdLoadFPGA {
     flash: Option<u32>,
 }
 
+/// Settings read from the config file, which holds `key = value` lines.
+/// Command-line flags take precedence.
+#[derive(Default)]
+struct Config {
+    serial_port: Option<String>,
+    command_timeout: Option<u64>,
+    drain_timeout: Option<u64>,
+    recovery_timeout: Option<u64>,
+    reconnect_timeout: Option<u64>,
+    retries: Option<usize>,
+    probe: Option<bool>,
+    log_level: Option<LevelFilter>,
+}
+
+impl Config {
+    fn default_path() -> Option<PathBuf> {
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config")))?;
+        Some(dir.join("megalink").join("config"))
+    }
+
+    /// Load the config from `path`, or from the default path if it exists.
+    fn load(path: Option<&Path>) -> anyhow::Result<Config> {
+        let path = match path {
+            Some(p) => p.to_path_buf(),
+            None => match Config::default_path() {
+                Some(p) if p.exists() => p,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow!("unable to read {}: {}", path.display(), e))?;
+        Config::parse(&text)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Config> {
+        let mut config = Config::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => Err(anyhow!("line {}: expected key = value", n + 1))?,
+            };
+            let invalid = |e: &dyn std::fmt::Display| anyhow!("line {}: invalid {}: {}", n + 1, key, e);
+
+            match key {
+                "serial-port" => config.serial_port = Some(value.to_string()),
+                "command-timeout" => config.command_timeout = Some(value.parse().map_err(|e| invalid(&e))?),
+                "drain-timeout" => config.drain_timeout = Some(value.parse().map_err(|e| invalid(&e))?),
+                "recovery-timeout" => config.recovery_timeout = Some(value.parse().map_err(|e| invalid(&e))?),
+                "reconnect-timeout" => config.reconnect_timeout = Some(value.parse().map_err(|e| invalid(&e))?),
+                "retries" => config.retries = Some(value.parse().map_err(|e| invalid(&e))?),
+                "probe" => config.probe = Some(value.parse().map_err(|e| invalid(&e))?),
+                "log-level" => config.log_level = Some(value.parse().map_err(|e| invalid(&e))?),
+                other => Err(anyhow!("line {}: unknown setting {}", n + 1, other))?,
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn parse_faults(spec: &str) -> anyhow::Result<Faults> {
+    let mut faults = Faults::default();
+    for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = match setting.find('=') {
+            Some(i) => (&setting[..i], &setting[i + 1..]),
+            None => (setting, ""),
+        };
+        let invalid = |e: &dyn std::fmt::Display| anyhow!("invalid fault {}: {}", key, e);
+        let chance = |value: &str| -> anyhow::Result<f64> {
+            let p: f64 = value.parse().map_err(|e| invalid(&e))?;
+            if !(0.0..=1.0).contains(&p) {
+                Err(invalid(&"must be between 0 and 1"))?;
+            }
+            Ok(p)
+        };
+
+        match key {
+            "seed" => faults.seed = value.parse().map_err(|e| invalid(&e))?,
+            "drop" => faults.drop = chance(value)?,
+            "duplicate" => faults.duplicate = chance(value)?,
+            "corrupt" => faults.corrupt = chance(value)?,
+            "delay" => faults.delay = chance(value)?,
+            "delay-time" => faults.delay_time = Duration::from_millis(value.parse().map_err(|e| invalid(&e))?),
+            "timeout-every" => faults.timeout_every = value.parse().map_err(|e| invalid(&e))?,
+            "writes" => faults.writes = true,
+            "mode-change" => {
+                faults.mode_change = match value {
+                    "none" => ModeChangeFault::None,
+                    "lose" => ModeChangeFault::Lose,
+                    "disconnect" => ModeChangeFault::Disconnect,
+                    _ if value.starts_with("fail-opens:") => {
+                        let n = value["fail-opens:".len()..].parse().map_err(|e| invalid(&e))?;
+                        ModeChangeFault::FailOpens(n)
+                    },
+                    other => Err(invalid(&other))?,
+                };
+            },
+            other => Err(anyhow!("unknown fault {}", other))?,
+        }
+    }
+    Ok(faults)
+}
+
 struct Factory {
+    #[cfg(feature = "serial")]
     port_name: Option<String>,
+    #[cfg(feature = "serial")]
     first: bool,
+    simulator: Option<SimulatorFactory>,
+    replay: Option<ReplayFactory>,
+    /// Kept between connections, so the server knows it is the same client.
+    shared: Option<TcpFactory>,
+    faults: Option<FaultInjector>,
+    capture: Option<Capture>,
 }
 
 impl SerialFactory for Factory {
-    fn open(&mut self) -> anyhow::Result<Box<dyn SerialPort>> {
+    type Transport = Box<dyn Transport>;
+
+    fn open(&mut self) -> megalink_rs::Result<Box<dyn Transport>> {
+        if let Some(faults) = self.faults.as_ref() {
+            faults.open()?;
+        }
+        let mut transport = self.open_device()?;
+        if let Some(faults) = self.faults.as_ref() {
+            transport = Box::new(faults.wrap(transport));
+        }
+        match self.capture.as_ref() {
+            Some(capture) => {
+                capture.record(Event::Open)?;
+                Ok(Box::new(capture.wrap(transport)))
+            },
+            None => Ok(transport),
+        }
+    }
+}
+
+impl Factory {
+    fn open_device(&mut self) -> megalink_rs::Result<Box<dyn Transport>> {
+        if let Some(replay) = self.replay.as_mut() {
+            return Ok(Box::new(replay.open()?));
+        }
+        if let Some(sim) = self.simulator.as_mut() {
+            return Ok(Box::new(sim.open()?));
+        }
+
+        if let Some(shared) = self.shared.as_mut() {
+            return Ok(Box::new(shared.open()?));
+        }
+
+        self.open_serial()
+    }
+
+    #[cfg(feature = "serial")]
+    fn open_serial(&mut self) -> megalink_rs::Result<Box<dyn Transport>> {
         let first = self.first;
         self.first = false;
 
@@ -74,12 +395,12 @@ impl SerialFactory for Factory {
             if ports.len() == 1 {
                 Ok(ports.into_iter().next().unwrap().port_name)
             } else {
-                let prefix = if ports.len() == 0 { "no" } else { "multiple" };
+                let prefix = if ports.is_empty() { "no" } else { "multiple" };
 
                 if first {
                     warn!("{} serial ports available, pick one with --serial-port=PATH.", prefix);
 
-                    if ports.len() > 0 {
+                    if !ports.is_empty() {
                         warn!("available serial ports:");
                         for port in serialport::available_ports()? {
                             warn!(" {}", port.port_name);
@@ -87,26 +408,395 @@ impl SerialFactory for Factory {
                     }
                 }
 
-                Err(anyhow!("unable to select serial port"))
+                Err(Error::other("unable to select serial port"))
             }
         }, Ok)?;
 
         info!("using serial port {}", &serial_port_path);
-        Ok(serialport::new(&serial_port_path, 9600).open()?)
+        let port: Box<dyn SerialPort> = serialport::new(&serial_port_path, 9600).open()?;
+        Ok(Box::new(port))
+    }
+
+    #[cfg(not(feature = "serial"))]
+    fn open_serial(&mut self) -> megalink_rs::Result<Box<dyn Transport>> {
+        Err(Error::other("built without serial port support, use --simulate or tcp://HOST:PORT"))
     }
 }
 
+const PROGRESS_BAR_WIDTH: usize = 30;
+
+/// A progress bar, drawn on stderr.
+struct ProgressBar;
+
+impl ProgressBar {
+    fn format_size(n: f64) -> String {
+        if n >= 1024.0 * 1024.0 {
+            format!("{:.1} MiB", n / (1024.0 * 1024.0))
+        } else if n >= 1024.0 {
+            format!("{:.1} KiB", n / 1024.0)
+        } else {
+            format!("{} B", n as u64)
+        }
+    }
+}
+
+impl Progress for ProgressBar {
+    fn update(&mut self, event: &ProgressEvent) {
+        let filled = (event.fraction() * PROGRESS_BAR_WIDTH as f64) as usize;
+        let bar = format!("{}{}", "#".repeat(filled), " ".repeat(PROGRESS_BAR_WIDTH - filled));
+
+        let mut stderr = std::io::stderr();
+        let _ = write!(stderr, "\r\x1b[K{:8} [{}] {:3.0}% {} / {} ({}/s)",
+                       event.phase.lower_name(),
+                       bar,
+                       event.fraction() * 100.0,
+                       ProgressBar::format_size(event.done as f64),
+                       ProgressBar::format_size(event.total as f64),
+                       ProgressBar::format_size(event.bytes_per_second()));
+        let _ = stderr.flush();
+    }
+
+    fn finish(&mut self, _event: &ProgressEvent) {
+        let mut stderr = std::io::stderr();
+        let _ = write!(stderr, "\r\x1b[K");
+        let _ = stderr.flush();
+    }
+}
+
+#[cfg(unix)]
+mod sigint {
+    use std::ptr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+    use megalink_rs::CancelToken;
+
+    static CANCEL_FLAG: AtomicPtr<AtomicBool> = AtomicPtr::new(ptr::null_mut());
+
+    extern "C" fn on_sigint(_: libc::c_int) {
+        let flag = CANCEL_FLAG.load(Ordering::SeqCst);
+        if flag.is_null() {
+            return;
+        }
+
+        // A second Ctrl-C exits immediately, in case the device is stuck.
+        let flag = unsafe { &*flag };
+        if flag.swap(true, Ordering::SeqCst) {
+            unsafe { libc::_exit(130) };
+        }
+    }
+
+    /// Cancel the token when Ctrl-C is pressed.
+    pub fn install(token: &CancelToken) {
+        let flag = Arc::into_raw(token.flag().clone()) as *mut AtomicBool;
+        CANCEL_FLAG.store(flag, Ordering::SeqCst);
+
+        let handler = on_sigint as extern "C" fn(libc::c_int);
+        unsafe {
+            // SA_RESTART keeps reads and writes on the port going, so the
+            // transfer can be finished off cleanly.
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(libc::SIGINT, &action, ptr::null_mut()) != 0 {
+                log::warn!("unable to handle Ctrl-C: {}", std::io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+/// The number of bytes needed to detect the system a ROM is for.
+const SYSTEM_PREFIX_SIZE: u64 = 0x8000;
+
+/// Open an input file, given its path. `-` is standard input.
+fn open_reader(path: &Path) -> anyhow::Result<Box<dyn Read>> {
+    if path.as_os_str() == "-" {
+        Ok(Box::new(std::io::stdin()))
+    } else {
+        Ok(Box::new(std::fs::File::open(path)?))
+    }
+}
+
+/// Returns true if an input is a regular file, which can be read more than
+/// once.
+fn is_regular_file(path: &Path) -> bool {
+    path.as_os_str() != "-" && std::fs::metadata(path).map(|m| m.is_file()).unwrap_or(false)
+}
+
+/// Open an input file to be streamed to the device, and find its length.
+///
+/// The length must be sent before the data, so it is `size` if given, or
+/// the size of the file. Inputs such as pipes need `size`.
+fn open_input(path: &Path, size: Option<u64>) -> anyhow::Result<(Box<dyn Read>, u64)> {
+    let len = match size {
+        Some(size) => size,
+        None if is_regular_file(path) => std::fs::metadata(path)?.len(),
+        None => Err(anyhow!("the size of {} is not known, so it must be given with --size", path.display()))?,
+    };
+    Ok((open_reader(path)?, len))
+}
+
+/// Get the file name to report for an input file.
+fn input_name(path: &Path) -> String {
+    if path.as_os_str() == "-" {
+        return "stdin".to_string();
+    }
+
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("rom")
+        .to_string()
+}
+
+fn rom_info(path: &Path) -> anyhow::Result<()> {
+    let mut contents = Vec::new();
+    open_reader(path)?.read_to_end(&mut contents)?;
+    let system = System::detect(&input_name(path), &contents);
+
+    println!("System:         {}", system.lower_name());
+    println!("Size:           {} bytes", contents.len());
+
+    let header = match Header::parse(&contents) {
+        Some(h) => h,
+        None => return Ok(()),
+    };
+
+    let checksum = rom::checksum(&contents);
+    let checksum_status = if checksum == header.checksum {
+        "ok".to_string()
+    } else {
+        format!("calculated {:04x}", checksum)
+    };
+    println!("Console:        {}", header.console);
+    println!("Copyright:      {}", header.copyright);
+    println!("Domestic title: {}", header.domestic_title);
+    println!("Overseas title: {}", header.overseas_title);
+    println!("Serial:         {}", header.serial);
+    println!("Checksum:       {:04x} ({})", header.checksum, checksum_status);
+    println!("Devices:        {}", header.devices);
+    println!("ROM:            {:08x}-{:08x}", header.rom_start, header.rom_end);
+    println!("RAM:            {:08x}-{:08x}", header.ram_start, header.ram_end);
+    match header.external_ram.as_ref() {
+        Some(ram) => println!("External RAM:   {:08x}-{:08x} (type {:02x})", ram.start, ram.end, ram.kind),
+        None => println!("External RAM:   none"),
+    }
+    println!("Regions:        {}", header.regions);
+    if system == System::Mars && !header.is_mars() {
+        warn!("32X ROM does not have a 32X console name in its header");
+    }
+    Ok(())
+}
+
+fn decode(path: &Path) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("unable to read {}: {}", path.display(), e))?;
+    let records = parse_capture(&text)?;
+
+    for (time, frame) in decode_capture(&records) {
+        println!("{:4}.{:06} {} {}", time.as_secs(), time.subsec_micros(), frame.direction(), frame);
+    }
+    Ok(())
+}
+
+/// A symlink which is removed when dropped, however the proxy stops.
+#[cfg(unix)]
+struct Link(PathBuf);
+
+#[cfg(unix)]
+impl Link {
+    fn create(target: &Path, link: &Path) -> anyhow::Result<Link> {
+        if link.symlink_metadata().is_ok() {
+            std::fs::remove_file(link)?;
+        }
+        std::os::unix::fs::symlink(target, link)?;
+        Ok(Link(link.to_path_buf()))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Link {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("unable to remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Forward traffic between a pseudo-terminal and the device, logging it.
+#[cfg(unix)]
+fn proxy(mut factory: Factory, c: &CmdProxy, reconnect: &ReconnectPolicy, cancel: &CancelToken) -> anyhow::Result<()> {
+    let mut pty = Pty::open()?;
+    let _link = c.link.as_ref()
+        .map(|link| Link::create(pty.path(), link))
+        .transpose()?;
+    println!("{}", pty.path().display());
+
+    let mut device = factory.open()?;
+    let mut decoder = Decoder::new();
+    log_frames(Level::Info, decoder.push(&Event::Open));
+
+    // The pseudo-terminal stays open, so this only stops when cancelled or
+    // the device goes away. It goes away when it changes mode, so wait for
+    // it to come back.
+    while let ForwardEnd::DeviceGone = forward(&mut pty, device.as_mut(), &mut decoder, Level::Info, cancel)? {
+        log_frames(Level::Info, decoder.push(&Event::Eof));
+        device = reopen(&mut factory, reconnect, cancel)?;
+        log_frames(Level::Info, decoder.push(&Event::Open));
+    }
+    log_frames(Level::Info, decoder.finish());
+    Ok(())
+}
+
+/// Share the device over TCP, with one client at a time.
+fn serve(factory: Factory, c: &CmdServe) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&c.addr)
+        .map_err(|e| anyhow!("unable to listen on {}: {}", c.addr, e))?;
+    info!("serving on {}", listener.local_addr()?);
+
+    let mut server = Server::new(factory);
+    server.set_hold(Duration::from_millis(c.hold));
+    server.run(listener);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn reopen(factory: &mut Factory, policy: &ReconnectPolicy, cancel: &CancelToken) -> anyhow::Result<Box<dyn Transport>> {
+    let start = std::time::Instant::now();
+    loop {
+        std::thread::sleep(policy.initial_delay);
+        match factory.open() {
+            Ok(device) => return Ok(device),
+            Err(e) if start.elapsed() >= policy.timeout || cancel.is_cancelled() => return Err(e.into()),
+            Err(_) => {},
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn proxy(_: Factory, _: &CmdProxy, _: &ReconnectPolicy, _: &CancelToken) -> anyhow::Result<()> {
+    Err(anyhow!("proxy is only supported on unix"))
+}
+
 fn main() -> anyhow::Result<()> {
+    let opts = Opts::parse();
+    let config = Config::load(opts.config.as_deref())?;
+
+    let log_level = match opts.verbose {
+        0 => config.log_level.unwrap_or(LevelFilter::Info),
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
     env_logger::Builder::from_env(
-        env_logger::Env::default().default_filter_or("info"))
+        env_logger::Env::default().default_filter_or(log_level.to_string()))
         .init();
-    let opts = Opts::parse();
 
-    let factory = Factory { port_name: opts.serial_port.clone(), first: true };
-    let mut everdrive = EverdriveSerial::new(factory)?;
+    match &opts.command {
+        Command::RomInfo(c) => rom_info(&c.path),
+        Command::Decode(c) => decode(&c.path),
+        Command::Proxy(c) => {
+            let (factory, _) = open_factory(&opts, &config)?;
+            let cancel = CancelToken::new();
+            #[cfg(unix)]
+            sigint::install(&cancel);
+            proxy(factory, c, &reconnect_policy(&opts, &config), &cancel)
+        },
+        Command::Serve(c) => serve(open_factory(&opts, &config)?.0, c),
+        Command::Device(c) => run_device(&opts, &config, c),
+    }
+}
+
+/// Create the factory for connecting to the device, as set up by the
+/// options. The replay is returned too, if there is one, so that it can be
+/// checked once the command has finished.
+fn open_factory(opts: &Opts, config: &Config) -> anyhow::Result<(Factory, Option<Replay>)> {
+    let simulator = match opts.simulate.as_ref() {
+        Some(dir) => {
+            let sim = Simulator::new();
+            sim.set_flash_file(dir.join("flash.bin"))?;
+            sim.set_sd_root(dir.join("sd"));
+            Some(sim.factory())
+        },
+        None => None,
+    };
+
+    let replay = match opts.replay.as_ref() {
+        Some(path) => Some(Replay::parse(&std::fs::read_to_string(path)?)?),
+        None => None,
+    };
+    let faults = match opts.faults.as_ref() {
+        Some(spec) => Some(FaultInjector::new(parse_faults(spec)?)),
+        None => None,
+    };
+    let capture = match opts.capture.as_ref() {
+        Some(path) => Some(Capture::new(std::fs::File::create(path)?)?),
+        None => None,
+    };
+
+    let port_name = port_name(opts, config);
+    let shared = match port_name.as_ref().and_then(|p| p.strip_prefix("tcp://")) {
+        Some(addr) => {
+            info!("using device shared at {}", addr);
+            Some(TcpFactory::new(addr))
+        },
+        None => None,
+    };
+
+    let factory = Factory {
+        #[cfg(feature = "serial")]
+        port_name,
+        #[cfg(feature = "serial")]
+        first: true,
+        simulator,
+        replay: replay.as_ref().map(Replay::factory),
+        shared,
+        faults,
+        capture,
+    };
+    Ok((factory, replay))
+}
+
+fn port_name(opts: &Opts, config: &Config) -> Option<String> {
+    opts.serial_port.clone().or_else(|| config.serial_port.clone())
+}
+
+fn reconnect_policy(opts: &Opts, config: &Config) -> ReconnectPolicy {
+    let mut reconnect = ReconnectPolicy::default();
+    if let Some(ms) = opts.reconnect_timeout.or(config.reconnect_timeout) {
+        reconnect.timeout = Duration::from_millis(ms);
+    }
+    reconnect
+}
+
+fn run_device(opts: &Opts, config: &Config, command: &DeviceCommand) -> anyhow::Result<()> {
+    let (factory, replay) = open_factory(opts, config)?;
 
-    match opts.command {
-        Command::SetMode(c) => {
+    let mut builder = EverdriveSerial::builder(factory)
+        .reconnect_policy(reconnect_policy(opts, config))
+        .probe(!opts.no_probe && config.probe.unwrap_or(true));
+    if let Some(ms) = opts.command_timeout.or(config.command_timeout) {
+        builder = builder.command_timeout(Duration::from_millis(ms));
+    }
+    if let Some(ms) = opts.drain_timeout.or(config.drain_timeout) {
+        builder = builder.drain_timeout(Duration::from_millis(ms));
+    }
+    if let Some(ms) = opts.recovery_timeout.or(config.recovery_timeout) {
+        builder = builder.recovery_timeout(Duration::from_millis(ms));
+    }
+    if let Some(retries) = opts.retries.or(config.retries) {
+        builder = builder.retries(retries);
+    }
+    let mut everdrive = builder.build()?;
+    if !opts.quiet && std::io::stderr().is_terminal() {
+        everdrive.set_progress(Some(Box::new(ProgressBar)));
+    }
+
+    let cancel = CancelToken::new();
+    #[cfg(unix)]
+    sigint::install(&cancel);
+    everdrive.set_cancel_token(Some(cancel));
+
+    match command {
+        DeviceCommand::SetMode(c) => {
             let mode = match c.mode.as_str() {
                 "app" => Mode::App,
                 "service" => Mode::Service,
@@ -114,32 +804,103 @@ fn main() -> anyhow::Result<()> {
             };
             everdrive.set_mode(mode)?;
         },
-        Command::Reset(c) => {
+        DeviceCommand::Reset(c) => {
             info!("resetting");
             let mode = if c.hard { ResetMode::Hard } else { ResetMode::Soft };
             everdrive.reset_host(mode)?;
         }
-        Command::Recover(_) => {
-          everdrive.recover()?;
+        DeviceCommand::Recover(_) => {
+            match everdrive.recover() {
+                Err(e) if e.status_code() == Some(StatusCode::CORE_MATCHES_RECOVERY) => {
+                    info!("nothing to recover: {}", e);
+                },
+                r => r?,
+            }
         },
-        Command::Run(c) => {
-            let contents = std::fs::read(&c.path)?;
-            let file_name = c.path.file_name().unwrap().to_str().unwrap();
-
-            if let Some(fpga_path) = c.fpga.as_ref() {
-                let fpga_bin = std::fs::read(fpga_path)?;
-                everdrive.load_fpga_from_slice(&fpga_bin)?;
+        DeviceCommand::Run(c) => {
+            if let Some(save_dir) = c.save_dir.as_ref() {
+                let mut save_sync = SaveSync::new(save_dir);
+                if let Some(size) = c.sram_size {
+                    save_sync.sram_size = size;
+                }
+                save_sync.restore = !c.no_restore;
+                everdrive.set_save_sync(Some(save_sync));
             }
 
-            everdrive.load_game(file_name, &contents, c.skip_fpga || c.fpga.is_some())?;
+            everdrive.set_verify(c.verify);
+
+            let core = if let Some(p) = c.fpga.as_ref() {
+                let mut data = Vec::new();
+                open_reader(p)?.read_to_end(&mut data)?;
+                Some(FpgaSource::Data(data))
+            } else if let Some(p) = c.fpga_sd.as_ref() {
+                Some(FpgaSource::Sd(p.clone()))
+            } else {
+                c.fpga_flash.map(FpgaSource::Flash)
+            };
+
+            let skip_fpga = c.skip_fpga || core.is_some();
+            match (c.path.as_ref(), c.sd.as_ref()) {
+                (Some(path), None) => {
+                    let (mut input, len) = open_input(path, c.size)?;
+                    let file_name = input_name(path);
+
+                    let mut prefix = Vec::new();
+                    (&mut input).take(SYSTEM_PREFIX_SIZE).read_to_end(&mut prefix)?;
+
+                    let system = match c.system.as_deref() {
+                        Some("md") => System::MegaDrive,
+                        Some("32x") => System::Mars,
+                        Some("sms") => System::MasterSystem,
+                        Some("gg") => System::GameGear,
+                        Some(other) => Err(anyhow!("unexpected system {}", other))?,
+                        None => System::detect(&file_name, &prefix),
+                    };
+                    info!("running {} game", system.lower_name());
+
+                    let mut info = match c.sd_path.as_ref() {
+                        Some(p) => GameInfo::sd(p),
+                        None => GameInfo::usb(&file_name),
+                    };
+                    info.skip_fpga = skip_fpga;
+
+                    if let Some(dat) = c.dat.as_ref() {
+                        let mut db = RomDb::new();
+                        db.load_dat(&std::fs::read_to_string(dat)?)?;
+
+                        // Identifying the game means reading all of it, so
+                        // streams which can only be read once are not.
+                        if is_regular_file(path) {
+                            if let Some(entry) = db.find_reader(std::fs::File::open(path)?.take(len))? {
+                                info!("identified as {}", entry.name);
+                                if entry.bad_dump {
+                                    warn!("{} is a known bad dump", entry.name);
+                                }
+                            }
+                        } else {
+                            warn!("not identifying the game, since it can only be read once");
+                        }
+                    }
+
+                    let input = std::io::Cursor::new(prefix).chain(input);
+                    everdrive.load_system_game_from_reader(system, core.as_ref(), &info, input, len)?;
+                },
+                (None, Some(sd)) => {
+                    if let Some(core) = core.as_ref() {
+                        everdrive.load_fpga(core)?;
+                    }
+                    everdrive.load_game_from_sd(sd, skip_fpga)?;
+                },
+                _ => unreachable!("clap requires exactly one of the path and --sd"),
+            }
         }
-        Command::LoadFPGA(c) => {
+        DeviceCommand::LoadFPGA(c) => {
             if let Some(p) = c.path.as_ref() {
-                let fpga_bin = std::fs::read(p)?;
-                everdrive.load_fpga_from_slice(&fpga_bin)?;
+                let (input, len) = open_input(p, c.size)?;
+                everdrive.load_fpga_from_reader(input, len)?;
             } else if let Some(p) = c.sd.as_ref() {
                 everdrive.load_fpga_from_sd(p)?;
-            } else if let Some(addr) = c.flash.clone() {
+            } else if let Some(addr) = c.flash {
                 everdrive.load_fpga_from_flash(addr)?;
             } else {
                 Err(anyhow!("load-fpga needs at least one path argument"))?;
@@ -148,5 +909,9 @@ fn main() -> anyhow::Result<()> {
     }
 
     everdrive.reset_host(ResetMode::Off)?;
+    if let Some(replay) = replay.as_ref() {
+        replay.finish()?;
+        info!("replay matched capture");
+    }
     Ok(())
 }
@@ -0,0 +1,95 @@
+//! Configuration for creating an `EverdriveSerial`.
+
+use std::time::Duration;
+use crate::{EverdriveSerial, ReconnectPolicy, Result, SerialFactory};
+
+/// Timeouts for operations on the serial port.
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    /// How long to wait for stray data when draining the port.
+    pub drain: Duration,
+    /// How long to wait for the response to a normal command.
+    pub command: Duration,
+    /// How long to wait for firmware recovery to finish.
+    pub recovery: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            drain: Duration::from_millis(100),
+            command: Duration::from_secs(1),
+            recovery: Duration::from_secs(8),
+        }
+    }
+}
+
+/// A builder for `EverdriveSerial`, created with `EverdriveSerial::builder`.
+pub struct EverdriveBuilder<F> {
+    factory: F,
+    timeouts: Timeouts,
+    retries: usize,
+    probe: bool,
+    reconnect: ReconnectPolicy,
+}
+
+impl<F: SerialFactory> EverdriveBuilder<F> {
+    pub(crate) fn new(factory: F) -> EverdriveBuilder<F> {
+        EverdriveBuilder {
+            factory,
+            timeouts: Timeouts::default(),
+            retries: 1,
+            probe: true,
+            reconnect: ReconnectPolicy::default(),
+        }
+    }
+
+    /// Set all of the timeouts.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Set how long to wait for stray data when draining the port.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.drain = timeout;
+        self
+    }
+
+    /// Set how long to wait for the response to a normal command.
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.command = timeout;
+        self
+    }
+
+    /// Set how long to wait for firmware recovery to finish.
+    pub fn recovery_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.recovery = timeout;
+        self
+    }
+
+    /// Set how many times idempotent commands are tried again after the
+    /// protocol gets out of step. The default is 1.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set whether to check the device responds when connecting. The
+    /// default is true.
+    pub fn probe(mut self, probe: bool) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    /// Set how to reconnect after the device re-enumerates.
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Connect to the device.
+    pub fn build(self) -> Result<EverdriveSerial<F>> {
+        EverdriveSerial::connect(self.factory, self.timeouts, self.retries, self.probe, self.reconnect)
+    }
+}
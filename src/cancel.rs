@@ -0,0 +1,42 @@
+//! Cancellation of in-flight operations.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A token which can be used to cancel bulk operations from another thread
+/// (or a signal handler).
+///
+/// Operations check the token between chunks. A cancelled operation leaves
+/// the device in a known state, and fails with `Error::Cancelled`. The token stays
+/// cancelled until it is reset.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Create a new token, which is not cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Get the flag which is set when the token is cancelled.
+    pub fn flag(&self) -> &Arc<AtomicBool> {
+        &self.flag
+    }
+
+    /// Cancel any operations using this token.
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Clear the cancellation, so the token can be used again.
+    pub fn reset(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,445 @@
+//! Recording and replaying the bytes exchanged with the device.
+//!
+//! A capture is a text file, with one event per line:
+//!
+//! ```text
+//! # megalink capture 1
+//! 0.000000 open
+//! 0.000012 cmd 10 status
+//! 0.000015 tx 2b d4 10 ef
+//! 0.000980 rx a5 00
+//! 0.101000 timeout
+//! ```
+//!
+//! Each line starts with the time since recording started, in seconds.
+//! `tx` is data written by the host and `rx` is data read from the device.
+//! `cmd` lines mark the start of each command, as reported by the host
+//! through `Transport::mark_command`, and are ignored on replay.
+
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::{command_name, Error, Result, SerialFactory, Transport};
+
+/// The first line of a capture file.
+pub const CAPTURE_HEADER: &str = "# megalink capture 1";
+
+/// The number of bytes written on each `tx` or `rx` line.
+const BYTES_PER_LINE: usize = 32;
+
+/// Something which happened on a connection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// A connection was opened.
+    Open,
+    /// The start of a command from the host.
+    Command(u8),
+    /// Data written by the host.
+    Tx(Vec<u8>),
+    /// Data read from the device.
+    Rx(Vec<u8>),
+    /// A read timed out.
+    Timeout,
+    /// A read found that the connection was closed.
+    Eof,
+    /// A read or write failed.
+    Error(String),
+}
+
+/// An event in a capture.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    /// The time since recording started.
+    pub time: Duration,
+    /// What happened.
+    pub event: Event,
+    /// The line of the capture file the event is on, or 0 if it was not
+    /// read from a file.
+    pub line: usize,
+}
+
+impl Record {
+    /// Format the record as lines of a capture file.
+    pub fn format(&self) -> String {
+        let time = format!("{}.{:06}", self.time.as_secs(), self.time.subsec_micros());
+        let mut out = String::new();
+        match &self.event {
+            Event::Open => writeln!(out, "{} open", time).unwrap(),
+            Event::Command(cmd) => {
+                writeln!(out, "{} cmd {:02x} {}", time, cmd, command_name(*cmd).unwrap_or("unknown")).unwrap()
+            },
+            Event::Tx(data) | Event::Rx(data) => {
+                let dir = if matches!(self.event, Event::Tx(_)) { "tx" } else { "rx" };
+                for chunk in data.chunks(BYTES_PER_LINE) {
+                    write!(out, "{} {}", time, dir).unwrap();
+                    for b in chunk {
+                        write!(out, " {:02x}", b).unwrap();
+                    }
+                    out.push('\n');
+                }
+            },
+            Event::Timeout => writeln!(out, "{} timeout", time).unwrap(),
+            Event::Eof => writeln!(out, "{} eof", time).unwrap(),
+            Event::Error(msg) => writeln!(out, "{} error {}", time, msg).unwrap(),
+        }
+        out
+    }
+}
+
+/// Parse a capture file.
+pub fn parse_capture(text: &str) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let err = |message: String| Error::Capture { line: n + 1, message };
+        let mut parts = line.splitn(3, ' ');
+        let time = parts.next().unwrap_or("");
+        let time = time.parse::<f64>()
+            .ok()
+            .filter(|t| t.is_finite() && *t >= 0.0)
+            .map(Duration::from_secs_f64)
+            .ok_or_else(|| err(format!("invalid time {}", time)))?;
+        let kind = parts.next().unwrap_or("");
+        let rest = parts.next().unwrap_or("");
+
+        let hex = |rest: &str| rest.split_whitespace()
+            .map(|b| u8::from_str_radix(b, 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|e| err(format!("invalid data: {}", e)));
+
+        let event = match kind {
+            "open" => Event::Open,
+            "cmd" => {
+                let cmd = rest.split_whitespace().next().unwrap_or("");
+                Event::Command(u8::from_str_radix(cmd, 16)
+                    .map_err(|e| err(format!("invalid command: {}", e)))?)
+            },
+            "tx" => Event::Tx(hex(rest)?),
+            "rx" => Event::Rx(hex(rest)?),
+            "timeout" => Event::Timeout,
+            "eof" => Event::Eof,
+            "error" => Event::Error(rest.to_string()),
+            other => return Err(err(format!("unknown event {}", other))),
+        };
+
+        // Join data split across lines back together.
+        match (records.last_mut(), &event) {
+            (Some(Record { time: t, event: Event::Tx(prev), .. }), Event::Tx(data)) |
+            (Some(Record { time: t, event: Event::Rx(prev), .. }), Event::Rx(data)) if *t == time => {
+                prev.extend_from_slice(data);
+            },
+            _ => records.push(Record { time, event, line: n + 1 }),
+        }
+    }
+    Ok(records)
+}
+
+struct CaptureInner {
+    out: Box<dyn Write + Send>,
+    start: Instant,
+}
+
+/// The destination of a recording. This can be cloned to share it between
+/// several connections.
+#[derive(Clone)]
+pub struct Capture {
+    inner: Arc<Mutex<CaptureInner>>,
+}
+
+impl Capture {
+    /// Start a recording, writing the capture to `out`.
+    pub fn new(out: impl Write + Send + 'static) -> Result<Capture> {
+        let mut out: Box<dyn Write + Send> = Box::new(out);
+        writeln!(out, "{}", CAPTURE_HEADER)?;
+        Ok(Capture {
+            inner: Arc::new(Mutex::new(CaptureInner {
+                out,
+                start: Instant::now(),
+            })),
+        })
+    }
+
+    /// Write an event to the capture.
+    pub fn record(&self, event: Event) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let time = inner.start.elapsed();
+        let text = Record { time, event, line: 0 }.format();
+        inner.out.write_all(text.as_bytes())?;
+        inner.out.flush()?;
+        Ok(())
+    }
+
+    /// Wrap a transport so that everything sent over it is recorded.
+    pub fn wrap<T: Transport>(&self, transport: T) -> Recorder<T> {
+        Recorder { inner: transport, capture: self.clone() }
+    }
+}
+
+fn record_error(e: Error) -> io::Error {
+    io::Error::other(format!("unable to write capture: {}", e))
+}
+
+/// A transport which records everything sent over it.
+pub struct Recorder<T> {
+    inner: T,
+    capture: Capture,
+}
+
+impl<T: Transport> Read for Recorder<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        let event = match &result {
+            Ok(0) if !buf.is_empty() => Event::Eof,
+            Ok(n) => Event::Rx(buf[..*n].to_vec()),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Event::Timeout,
+            Err(e) => Event::Error(e.to_string()),
+        };
+        self.capture.record(event).map_err(record_error)?;
+        result
+    }
+}
+
+impl<T: Transport> Write for Recorder<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.inner.write(buf);
+        let event = match &result {
+            Ok(n) => Event::Tx(buf[..*n].to_vec()),
+            Err(e) => Event::Error(e.to_string()),
+        };
+        self.capture.record(event).map_err(record_error)?;
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for Recorder<T> {
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn mark_command(&mut self, cmd: u8) -> Result<()> {
+        self.capture.record(Event::Command(cmd))?;
+        self.inner.mark_command(cmd)
+    }
+}
+
+/// A factory which records every connection opened by another factory.
+pub struct RecordingFactory<F> {
+    inner: F,
+    capture: Capture,
+}
+
+impl<F: SerialFactory> RecordingFactory<F> {
+    /// Record the connections opened by `inner` to `capture`.
+    pub fn new(inner: F, capture: Capture) -> RecordingFactory<F> {
+        RecordingFactory { inner, capture }
+    }
+}
+
+impl<F: SerialFactory> SerialFactory for RecordingFactory<F> {
+    type Transport = Recorder<F::Transport>;
+
+    fn open(&mut self) -> Result<Recorder<F::Transport>> {
+        let transport = self.inner.open()?;
+        self.capture.record(Event::Open)?;
+        Ok(self.capture.wrap(transport))
+    }
+}
+
+struct ReplayState {
+    records: Vec<Record>,
+    next: usize,
+    /// How much of the current `tx` or `rx` record has been used.
+    used: usize,
+}
+
+impl ReplayState {
+    fn skip_commands(&mut self) {
+        while matches!(self.records.get(self.next), Some(Record { event: Event::Command(_), .. })) {
+            self.next += 1;
+        }
+    }
+
+    fn advance(&mut self) {
+        self.next += 1;
+        self.used = 0;
+    }
+
+    fn diverged(&self, message: String) -> Error {
+        let line = self.records.get(self.next).map_or(0, |r| r.line);
+        Error::ReplayDiverged { line, message }
+    }
+
+    fn expected(&self) -> String {
+        match self.records.get(self.next) {
+            Some(r) => r.format().trim_end().to_string(),
+            None => "the end of the capture".to_string(),
+        }
+    }
+}
+
+/// Plays a capture back, in place of a device.
+///
+/// Data written by the host must match the capture exactly. If it does not,
+/// the write fails with `Error::ReplayDiverged`. Timing is not reproduced.
+#[derive(Clone)]
+pub struct Replay {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+impl Replay {
+    /// Create a replay of a parsed capture.
+    pub fn new(records: Vec<Record>) -> Replay {
+        Replay {
+            state: Arc::new(Mutex::new(ReplayState { records, next: 0, used: 0 })),
+        }
+    }
+
+    /// Parse a capture file, and create a replay of it.
+    pub fn parse(text: &str) -> Result<Replay> {
+        Ok(Replay::new(parse_capture(text)?))
+    }
+
+    /// Check that the whole capture was played back.
+    pub fn finish(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.skip_commands();
+        if state.next < state.records.len() {
+            return Err(state.diverged(format!("host stopped, but the capture expects {}", state.expected())));
+        }
+        Ok(())
+    }
+
+    /// Get a factory which opens connections from the replay.
+    pub fn factory(&self) -> ReplayFactory {
+        ReplayFactory { replay: self.clone() }
+    }
+
+    fn transport(&self) -> ReplayTransport {
+        ReplayTransport { replay: self.clone(), timeout: Duration::from_secs(1) }
+    }
+}
+
+/// A factory which opens connections from a `Replay`. Each call to `open`
+/// must match an `open` event in the capture.
+pub struct ReplayFactory {
+    replay: Replay,
+}
+
+impl SerialFactory for ReplayFactory {
+    type Transport = ReplayTransport;
+
+    fn open(&mut self) -> Result<ReplayTransport> {
+        let mut state = self.replay.state.lock().unwrap();
+        state.skip_commands();
+        match state.records.get(state.next).map(|r| &r.event) {
+            Some(Event::Open) => {
+                state.advance();
+                Ok(self.replay.transport())
+            },
+            Some(Event::Error(msg)) => {
+                let err = Error::other(msg.clone());
+                state.advance();
+                Err(err)
+            },
+            _ => Err(state.diverged(format!("host opened a connection, but the capture expects {}", state.expected()))),
+        }
+    }
+}
+
+/// A connection opened from a `Replay`.
+pub struct ReplayTransport {
+    replay: Replay,
+    timeout: Duration,
+}
+
+impl Read for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.replay.state.lock().unwrap();
+        state.skip_commands();
+        let used = state.used;
+        let event = state.records.get(state.next).map(|r| r.event.clone());
+        match event {
+            Some(Event::Rx(data)) => {
+                let n = buf.len().min(data.len() - used);
+                buf[..n].copy_from_slice(&data[used..used + n]);
+                state.used += n;
+                if state.used == data.len() {
+                    state.advance();
+                }
+                Ok(n)
+            },
+            Some(Event::Timeout) => {
+                state.advance();
+                Err(io::Error::new(ErrorKind::TimedOut, "replayed timeout"))
+            },
+            Some(Event::Eof) => {
+                state.advance();
+                Ok(0)
+            },
+            Some(Event::Error(msg)) => {
+                state.advance();
+                Err(io::Error::other(msg))
+            },
+            _ => Err(state.diverged(format!("host read, but the capture expects {}", state.expected())).into()),
+        }
+    }
+}
+
+impl Write for ReplayTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.replay.state.lock().unwrap();
+        state.skip_commands();
+        let used = state.used;
+        let event = state.records.get(state.next).map(|r| r.event.clone());
+        match event {
+            Some(Event::Tx(data)) => {
+                let n = buf.len().min(data.len() - used);
+                if buf[..n] != data[used..used + n] {
+                    let offset = buf[..n].iter().zip(&data[used..]).position(|(a, b)| a != b).unwrap_or(0);
+                    return Err(state.diverged(format!(
+                        "host wrote {:02x} at offset {}, but the capture has {:02x}",
+                        buf[offset], used + offset, data[used + offset])).into());
+                }
+
+                state.used += n;
+                if state.used == data.len() {
+                    state.advance();
+                }
+                Ok(n)
+            },
+            Some(Event::Error(msg)) => {
+                state.advance();
+                Err(io::Error::other(msg))
+            },
+            _ => Err(state.diverged(format!("host wrote data, but the capture expects {}", state.expected())).into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! Stream access to the Mega Everdrive Pro's memory.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use log::warn;
+use crate::progress::{Phase, Progress, Tracker};
+use crate::{EverdriveSerial, Region, Result, SerialFactory};
+
+/// The default size of each transfer made by a cursor.
+pub const DEFAULT_CHUNK_SIZE: usize = 0x10000;
+
+/// A cursor over a region of the Mega Everdrive Pro's memory, which allows it
+/// to be used as a stream.
+///
+/// Writes are buffered into chunks, and sent when a chunk is full, the
+/// cursor is moved, or the cursor is flushed. Dropping the cursor flushes
+/// it, but errors are only logged, so call `flush` to see them.
+///
+/// A cursor over the FIFO always transfers at the FIFO's port address, and
+/// can not seek.
+pub struct MemoryCursor<'a, F: SerialFactory> {
+    device: &'a mut EverdriveSerial<F>,
+    region: Region,
+    pos: u64,
+    chunk_size: usize,
+    write_buf: Vec<u8>,
+    progress: Option<Box<dyn Progress + 'a>>,
+    progress_total: u64,
+    tracker: Option<Tracker>,
+}
+
+impl<'a, F: SerialFactory> MemoryCursor<'a, F> {
+    /// Create a new cursor at the start of a region.
+    pub fn new(device: &'a mut EverdriveSerial<F>, region: Region) -> MemoryCursor<'a, F> {
+        MemoryCursor {
+            device,
+            region,
+            pos: 0,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            write_buf: Vec::new(),
+            progress: None,
+            progress_total: 0,
+            tracker: None,
+        }
+    }
+
+    /// Get the region the cursor is over.
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Set the maximum size of each transfer.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+
+    /// Set the receiver for progress updates, sent after each transfer.
+    /// `total` is the number of bytes expected to be transferred.
+    ///
+    /// Reads are reported as downloads and writes as uploads. Each time the
+    /// cursor switches between them, the previous phase is finished.
+    pub fn set_progress(&mut self, progress: Option<Box<dyn Progress + 'a>>, total: u64) {
+        self.finish_progress();
+        self.progress = progress;
+        self.progress_total = total;
+    }
+
+    fn remaining(&self) -> u64 {
+        match self.region.size() {
+            Some(size) => (size as u64).saturating_sub(self.pos),
+            None => u64::MAX,
+        }
+    }
+
+    /// Get the offset in the region of a transfer starting at `pos`.
+    fn offset(&self, pos: u64) -> io::Result<u32> {
+        if self.region.size().is_none() {
+            return Ok(0);
+        }
+        u32::try_from(pos).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "cursor position is outside the region")
+        })
+    }
+
+    fn report(&mut self, phase: Phase, n: usize) {
+        if self.progress.is_none() {
+            return;
+        }
+
+        if self.tracker.as_ref().map(|t| t.event().phase) != Some(phase) {
+            self.finish_progress();
+            self.tracker = Some(Tracker::new(phase, self.progress_total));
+        }
+
+        if let (Some(tracker), Some(progress)) = (self.tracker.as_mut(), self.progress.as_mut()) {
+            tracker.add(n as u64);
+            progress.update(&tracker.event());
+        }
+    }
+
+    fn finish_progress(&mut self) {
+        if let (Some(tracker), Some(progress)) = (self.tracker.take(), self.progress.as_mut()) {
+            progress.finish(&tracker.event());
+        }
+    }
+
+    fn flush_writes(&mut self) -> Result<()> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+
+        let data = std::mem::take(&mut self.write_buf);
+        let offset = self.offset(self.pos - data.len() as u64)?;
+        self.device.write_region(self.region, offset, &data)?;
+        self.report(Phase::Upload, data.len());
+
+        self.write_buf = data;
+        self.write_buf.clear();
+        Ok(())
+    }
+}
+
+impl<'a, F: SerialFactory> Read for MemoryCursor<'a, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.flush_writes().map_err(io::Error::from)?;
+
+        let n = buf.len()
+            .min(self.chunk_size)
+            .min(self.remaining() as usize);
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let offset = self.offset(self.pos)?;
+        self.device.read_region(self.region, offset, &mut buf[..n])
+            .map_err(io::Error::from)?;
+        self.pos += n as u64;
+        self.report(Phase::Download, n);
+        Ok(n)
+    }
+}
+
+impl<'a, F: SerialFactory> Write for MemoryCursor<'a, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The chunk size may have shrunk since the buffer was filled.
+        if self.write_buf.len() >= self.chunk_size {
+            self.flush_writes().map_err(io::Error::from)?;
+        }
+
+        let n = buf.len()
+            .min(self.chunk_size - self.write_buf.len())
+            .min(self.remaining() as usize);
+        if n == 0 {
+            return Ok(0);
+        }
+
+        self.write_buf.extend_from_slice(&buf[..n]);
+        self.pos += n as u64;
+
+        if self.write_buf.len() >= self.chunk_size {
+            self.flush_writes().map_err(io::Error::from)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_writes().map_err(io::Error::from)
+    }
+}
+
+impl<'a, F: SerialFactory> Seek for MemoryCursor<'a, F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.flush_writes().map_err(io::Error::from)?;
+
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let size = match self.region.size() {
+            Some(size) => size,
+            None => return Err(invalid("the fifo is a port, so it can not seek")),
+        };
+        let (base, offset) = match pos {
+            SeekFrom::Start(p) => (0, i64::try_from(p).map_err(|_| invalid("seek position is too large"))?),
+            SeekFrom::End(d) => (size as u64, d),
+            SeekFrom::Current(d) => (self.pos, d),
+        };
+
+        let new_pos = i64::try_from(base).ok()
+            .and_then(|b| b.checked_add(offset))
+            .ok_or_else(|| invalid("seek position is too large"))?;
+        if new_pos < 0 {
+            return Err(invalid("seek to before the start of the region"));
+        }
+
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl<'a, F: SerialFactory> Drop for MemoryCursor<'a, F> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_writes() {
+            warn!("failed to flush memory cursor: {}", e);
+        }
+        self.finish_progress();
+    }
+}
@@ -0,0 +1,736 @@
+//! Decoding the bytes exchanged with the device into protocol frames.
+//!
+//! The decoder follows both directions of a connection in the order the host
+//! saw them, as in a capture, and uses each command to work out what the
+//! following bytes mean.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+use byteorder::{ByteOrder, BigEndian};
+use crate::capture::{Event, Record};
+use crate::menu::MENU_READY;
+use crate::{command_name, MenuCommand, MenuResponse, Region, StatusCode};
+use crate::{ACK_BLOCK_SIZE, MODE_SERVICE, PACKET_CMD};
+use crate::{CMD_FLA_RD, CMD_FLA_WR, CMD_FPG_FLA, CMD_FPG_SDC, CMD_FPG_USB, CMD_F_FINFO, CMD_F_FOPN};
+use crate::{CMD_GET_MODE, CMD_HOST_RST, CMD_IO_RST, CMD_MEM_RD, CMD_MEM_WR, CMD_RUN_APP, CMD_STATUS, CMD_USB_RECOV};
+
+/// The number of bytes shown for data which could not be decoded.
+const UNKNOWN_PREVIEW: usize = 16;
+
+/// A command sent by the host, with its arguments.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    Status,
+    GetMode,
+    IoReset,
+    RunApp,
+    HostReset(u8),
+    MemRead { addr: u32, len: u32 },
+    MemWrite { addr: u32, len: u32 },
+    FlashRead { addr: u32, len: u32 },
+    FlashWrite { addr: u32, len: u32 },
+    FpgaUsb { len: u32 },
+    FpgaSd { size: u32 },
+    FpgaFlash { addr: u32 },
+    FileOpen { mode: u8, path: String },
+    FileInfo { path: String },
+    UsbRecover { addr: u32, crc: u32 },
+    /// A command which is not known, so its arguments can not be decoded.
+    Unknown(u8),
+}
+
+impl Command {
+    /// Decode the arguments of a command.
+    ///
+    /// Returns the command and the number of bytes of arguments, or `None` if
+    /// `args` is not long enough yet.
+    fn decode(cmd: u8, args: &[u8]) -> Option<(Command, usize)> {
+        let mut r = ArgReader { data: args, pos: 0 };
+        let command = match cmd {
+            CMD_STATUS => Command::Status,
+            CMD_GET_MODE => Command::GetMode,
+            CMD_IO_RST => {
+                r.u8()?;
+                Command::IoReset
+            },
+            CMD_RUN_APP => Command::RunApp,
+            CMD_HOST_RST => Command::HostReset(r.u8()?),
+            CMD_MEM_RD | CMD_MEM_WR => {
+                let addr = r.u32()?;
+                let len = r.u32()?;
+                r.u8()?;
+                if cmd == CMD_MEM_RD {
+                    Command::MemRead { addr, len }
+                } else {
+                    Command::MemWrite { addr, len }
+                }
+            },
+            CMD_FLA_RD => Command::FlashRead { addr: r.u32()?, len: r.u32()? },
+            CMD_FLA_WR => Command::FlashWrite { addr: r.u32()?, len: r.u32()? },
+            CMD_FPG_USB => Command::FpgaUsb { len: r.u32()? },
+            CMD_FPG_SDC => {
+                let size = r.u32()?;
+                r.u8()?;
+                Command::FpgaSd { size }
+            },
+            CMD_FPG_FLA => Command::FpgaFlash { addr: r.u32()? },
+            CMD_F_FOPN => {
+                let mode = r.u8()?;
+                Command::FileOpen { mode, path: r.str()? }
+            },
+            CMD_F_FINFO => Command::FileInfo { path: r.str()? },
+            CMD_USB_RECOV => Command::UsbRecover { addr: r.u32()?, crc: r.u32()? },
+            other => Command::Unknown(other),
+        };
+        Some((command, r.pos))
+    }
+
+    /// Get the command byte.
+    pub fn code(&self) -> u8 {
+        match self {
+            Command::Status => CMD_STATUS,
+            Command::GetMode => CMD_GET_MODE,
+            Command::IoReset => CMD_IO_RST,
+            Command::RunApp => CMD_RUN_APP,
+            Command::HostReset(_) => CMD_HOST_RST,
+            Command::MemRead { .. } => CMD_MEM_RD,
+            Command::MemWrite { .. } => CMD_MEM_WR,
+            Command::FlashRead { .. } => CMD_FLA_RD,
+            Command::FlashWrite { .. } => CMD_FLA_WR,
+            Command::FpgaUsb { .. } => CMD_FPG_USB,
+            Command::FpgaSd { .. } => CMD_FPG_SDC,
+            Command::FpgaFlash { .. } => CMD_FPG_FLA,
+            Command::FileOpen { .. } => CMD_F_FOPN,
+            Command::FileInfo { .. } => CMD_F_FINFO,
+            Command::UsbRecover { .. } => CMD_USB_RECOV,
+            Command::Unknown(cmd) => *cmd,
+        }
+    }
+
+    /// What the decoder should expect after this command, in order.
+    ///
+    /// The status which follows a command that changes state is expected
+    /// too. This host reads it with a separate status command, in which
+    /// case the expectation is dropped as soon as the host sends first.
+    fn expects(&self) -> Vec<Expect> {
+        match self {
+            Command::Status => vec![Expect::Status],
+            Command::GetMode => vec![Expect::Mode],
+            Command::MemRead { len, .. } | Command::FlashRead { len, .. } => {
+                vec![Expect::DeviceData { remaining: *len as usize, len: *len as usize }]
+            },
+            Command::MemWrite { addr, len } => vec![Expect::HostData {
+                remaining: *len as usize,
+                len: *len as usize,
+                fifo: Region::containing(*addr, *len as usize) == Some(Region::Fifo),
+            }],
+            Command::FlashWrite { len, .. } | Command::FpgaUsb { len } => {
+                vec![Expect::Acked { remaining: *len as usize, ack: true }, Expect::Status]
+            },
+            Command::FpgaFlash { .. } | Command::FpgaSd { .. } | Command::FileOpen { .. } |
+            Command::UsbRecover { .. } => vec![Expect::Status],
+            Command::FileInfo { .. } => vec![Expect::FileInfo],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = command_name(self.code()).unwrap_or("unknown");
+        match self {
+            Command::HostReset(mode) => {
+                let mode = match mode {
+                    0 => "off",
+                    1 => "soft",
+                    2 => "hard",
+                    _ => "unknown",
+                };
+                write!(f, "{} {}", name, mode)
+            },
+            Command::MemRead { addr, len } | Command::MemWrite { addr, len } => {
+                match Region::containing(*addr, *len as usize) {
+                    Some(r) => write!(f, "{} {} +{:x}, {} bytes", name, r.lower_name(), addr - r.base(), len),
+                    None => write!(f, "{} {:x}, {} bytes", name, addr, len),
+                }
+            },
+            Command::FlashRead { addr, len } | Command::FlashWrite { addr, len } => {
+                write!(f, "{} {:x}, {} bytes", name, addr, len)
+            },
+            Command::FpgaUsb { len } => write!(f, "{} {} bytes", name, len),
+            Command::FpgaSd { size } => write!(f, "{} {} bytes", name, size),
+            Command::FpgaFlash { addr } => write!(f, "{} {:x}", name, addr),
+            Command::FileOpen { mode, path } => write!(f, "{} {} (mode {:02x})", name, path, mode),
+            Command::FileInfo { path } => write!(f, "{} {}", name, path),
+            Command::UsbRecover { addr, crc } => write!(f, "{} {:x} (crc {:08x})", name, addr, crc),
+            Command::Unknown(cmd) => write!(f, "unknown command {:02x}", cmd),
+            _ => write!(f, "{}", name),
+        }
+    }
+}
+
+struct ArgReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ArgReader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let data = self.data.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(data)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(BigEndian::read_u16(self.take(2)?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(BigEndian::read_u32(self.take(4)?))
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let len = self.u16()? as usize;
+        Some(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+}
+
+/// A decoded piece of the protocol.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Frame {
+    /// A connection was opened.
+    Open,
+    /// A command from the host.
+    Command(Command),
+    /// A status response from the device.
+    Status(u16),
+    /// The mode reported by the device.
+    Mode(u8),
+    /// Bulk data sent by the host.
+    HostData(usize),
+    /// Bulk data sent by the device.
+    DeviceData(usize),
+    /// The device accepting (0) or rejecting the next block of a transfer.
+    Ack(u8),
+    /// A block of an acknowledged transfer, sent by the host.
+    Block(usize),
+    /// The response to a file information request.
+    FileInfo {
+        /// The status of the request.
+        code: StatusCode,
+        /// The size and name of the file, if it was found.
+        file: Option<(u32, String)>,
+    },
+    /// A command written to the menu's FIFO.
+    Menu(MenuCommand),
+    /// The menu's response to a command.
+    MenuResponse(u8),
+    /// The menu has started.
+    MenuReady,
+    /// A read timed out.
+    Timeout,
+    /// The connection was closed.
+    Eof,
+    /// A read or write failed.
+    Error(String),
+    /// Data which could not be decoded.
+    Unknown {
+        /// Whether the host sent the data.
+        host: bool,
+        /// The data.
+        data: Vec<u8>,
+    },
+}
+
+impl Frame {
+    /// Returns true if the host sent this frame, false if the device did,
+    /// or `None` if it is not data.
+    pub fn host_sent(&self) -> Option<bool> {
+        match self {
+            Frame::Command(_) | Frame::HostData(_) | Frame::Block(_) | Frame::Menu(_) => Some(true),
+            Frame::Status(_) | Frame::Mode(_) | Frame::DeviceData(_) | Frame::Ack(_) |
+            Frame::FileInfo { .. } | Frame::MenuResponse(_) | Frame::MenuReady => Some(false),
+            Frame::Unknown { host, .. } => Some(*host),
+            Frame::Open | Frame::Timeout | Frame::Eof | Frame::Error(_) => None,
+        }
+    }
+
+    /// Get an arrow showing which side sent this frame.
+    pub fn direction(&self) -> &'static str {
+        match self.host_sent() {
+            Some(true) => ">",
+            Some(false) => "<",
+            None => "-",
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Frame::Open => write!(f, "connection opened"),
+            Frame::Command(cmd) => write!(f, "{}", cmd),
+            Frame::Status(v) if v & 0xff00 == 0xa500 => write!(f, "status response: {}", StatusCode(*v as u8)),
+            Frame::Status(v) => write!(f, "invalid status {:04x}", v),
+            Frame::Mode(MODE_SERVICE) => write!(f, "mode: service"),
+            Frame::Mode(v) => write!(f, "mode: app ({:02x})", v),
+            Frame::HostData(len) | Frame::DeviceData(len) => write!(f, "data: {} bytes", len),
+            Frame::Ack(0) => write!(f, "ack"),
+            Frame::Ack(v) => write!(f, "rejected: {:02x}", v),
+            Frame::Block(len) => write!(f, "block: {} bytes", len),
+            Frame::FileInfo { file: Some((size, name)), .. } => write!(f, "file info: {}, {} bytes", name, size),
+            Frame::FileInfo { code, file: None } => write!(f, "file info: {}", code),
+            Frame::Menu(MenuCommand::StartGame { size, path }) => {
+                write!(f, "menu start-game {}, {} bytes", path, size)
+            },
+            Frame::Menu(cmd) => write!(f, "menu {}", cmd.name()),
+            Frame::MenuResponse(v) if v.is_ascii_graphic() => write!(f, "menu response {:02x} '{}'", v, *v as char),
+            Frame::MenuResponse(v) => write!(f, "menu response {:02x}", v),
+            Frame::MenuReady => write!(f, "menu ready"),
+            Frame::Timeout => write!(f, "timeout"),
+            Frame::Eof => write!(f, "disconnected"),
+            Frame::Error(msg) => write!(f, "error: {}", msg),
+            Frame::Unknown { data, .. } => {
+                write!(f, "unknown {} bytes:", data.len())?;
+                for b in data.iter().take(UNKNOWN_PREVIEW) {
+                    write!(f, " {:02x}", b)?;
+                }
+                if data.len() > UNKNOWN_PREVIEW {
+                    write!(f, " ...")?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// What the decoder expects next.
+#[derive(Clone, Copy, Debug)]
+enum Expect {
+    Status,
+    Mode,
+    FileInfo,
+    HostData { remaining: usize, len: usize, fifo: bool },
+    DeviceData { remaining: usize, len: usize },
+    /// An acknowledged transfer. `ack` is set when the device's
+    /// acknowledgement is due, rather than the host's block.
+    Acked { remaining: usize, ack: bool },
+    MenuResponse,
+}
+
+impl Expect {
+    fn host_sends(&self) -> bool {
+        match self {
+            Expect::HostData { .. } => true,
+            Expect::Acked { ack, .. } => !ack,
+            _ => false,
+        }
+    }
+}
+
+/// Decodes the bytes of a connection into frames.
+#[derive(Default)]
+pub struct Decoder {
+    tx: VecDeque<u8>,
+    rx: VecDeque<u8>,
+    expect: VecDeque<Expect>,
+    fifo: Vec<u8>,
+    out: Vec<Frame>,
+}
+
+impl Decoder {
+    /// Create a decoder for a new connection.
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// Add data written by the host, and return any frames it completes.
+    pub fn push_tx(&mut self, data: &[u8]) -> Vec<Frame> {
+        self.tx.extend(data);
+        self.run();
+        std::mem::take(&mut self.out)
+    }
+
+    /// Add data read from the device, and return any frames it completes.
+    pub fn push_rx(&mut self, data: &[u8]) -> Vec<Frame> {
+        self.rx.extend(data);
+        self.run();
+        std::mem::take(&mut self.out)
+    }
+
+    /// Add an event from a capture, and return any frames it completes.
+    pub fn push(&mut self, event: &Event) -> Vec<Frame> {
+        match event {
+            Event::Tx(data) => return self.push_tx(data),
+            Event::Rx(data) => return self.push_rx(data),
+            Event::Command(_) => {},
+            Event::Open => {
+                self.reset();
+                self.out.push(Frame::Open);
+            },
+            Event::Eof => {
+                self.reset();
+                self.out.push(Frame::Eof);
+            },
+            Event::Timeout => self.out.push(Frame::Timeout),
+            Event::Error(msg) => self.out.push(Frame::Error(msg.clone())),
+        }
+        std::mem::take(&mut self.out)
+    }
+
+    /// Return anything left over as undecoded data.
+    pub fn finish(&mut self) -> Vec<Frame> {
+        self.reset();
+        std::mem::take(&mut self.out)
+    }
+
+    fn reset(&mut self) {
+        self.expect.clear();
+        self.fifo.clear();
+        if !self.tx.is_empty() {
+            let data = self.tx.drain(..).collect();
+            self.out.push(Frame::Unknown { host: true, data });
+        }
+        if !self.rx.is_empty() {
+            let data = self.rx.drain(..).collect();
+            self.out.push(Frame::Unknown { host: false, data });
+        }
+    }
+
+    fn run(&mut self) {
+        while self.step() {}
+    }
+
+    /// Decode the next frame. Returns false if more data is needed.
+    fn step(&mut self) -> bool {
+        let expect = match self.expect.front().copied() {
+            Some(e) => e,
+            None => return self.step_idle(),
+        };
+
+        // Data is pushed in the order the host saw it, so if the other side
+        // has moved on, what was expected is never coming.
+        let (waiting, other) = if expect.host_sends() {
+            (&self.tx, &self.rx)
+        } else {
+            (&self.rx, &self.tx)
+        };
+        if waiting.is_empty() {
+            if !other.is_empty() {
+                self.expect.pop_front();
+                return true;
+            }
+            return false;
+        }
+
+        match expect {
+            Expect::Status => {
+                if self.rx.len() < 2 {
+                    return false;
+                }
+                let v = u16::from_be_bytes([self.rx[0], self.rx[1]]);
+                self.rx.drain(..2);
+                self.complete(Frame::Status(v));
+            },
+            Expect::Mode => {
+                let v = self.rx.pop_front().unwrap();
+                self.complete(Frame::Mode(v));
+            },
+            Expect::MenuResponse => {
+                let v = self.rx.pop_front().unwrap();
+                self.complete(Frame::MenuResponse(v));
+            },
+            Expect::FileInfo => return self.step_file_info(),
+            Expect::HostData { remaining, len, fifo } => {
+                let n = remaining.min(self.tx.len());
+                let data = self.tx.drain(..n);
+                if fifo {
+                    self.fifo.extend(data);
+                } else {
+                    drop(data);
+                }
+
+                if n < remaining {
+                    self.expect[0] = Expect::HostData { remaining: remaining - n, len, fifo };
+                } else if fifo {
+                    self.expect.pop_front();
+                    self.decode_fifo();
+                } else {
+                    self.complete(Frame::HostData(len));
+                }
+            },
+            Expect::DeviceData { remaining, len } => {
+                let n = remaining.min(self.rx.len());
+                self.rx.drain(..n);
+                if n < remaining {
+                    self.expect[0] = Expect::DeviceData { remaining: remaining - n, len };
+                } else {
+                    self.complete(Frame::DeviceData(len));
+                }
+            },
+            Expect::Acked { remaining, ack: true } => {
+                let v = self.rx.pop_front().unwrap();
+                self.out.push(Frame::Ack(v));
+                if v == 0 {
+                    self.expect[0] = Expect::Acked { remaining, ack: false };
+                } else {
+                    self.expect.pop_front();
+                }
+            },
+            Expect::Acked { remaining, ack: false } => {
+                let n = remaining.min(ACK_BLOCK_SIZE);
+                if self.tx.len() < n {
+                    return false;
+                }
+                self.tx.drain(..n);
+                self.out.push(Frame::Block(n));
+                if n < remaining {
+                    self.expect[0] = Expect::Acked { remaining: remaining - n, ack: true };
+                } else {
+                    self.expect.pop_front();
+                }
+            },
+        }
+        true
+    }
+
+    fn complete(&mut self, frame: Frame) {
+        self.expect.pop_front();
+        self.out.push(frame);
+    }
+
+    /// Decode data which is not a response to anything.
+    fn step_idle(&mut self) -> bool {
+        if self.tx.len() >= 4 {
+            return self.step_command();
+        }
+
+        // Bytes from the device which were not asked for are usually the
+        // menu starting.
+        match self.rx.pop_front() {
+            Some(MENU_READY) => self.out.push(Frame::MenuReady),
+            Some(v) => self.out.push(Frame::Unknown { host: false, data: vec![v] }),
+            None => return false,
+        }
+        true
+    }
+
+    fn step_command(&mut self) -> bool {
+        let valid = self.tx[0] == PACKET_CMD
+            && self.tx[1] == !PACKET_CMD
+            && self.tx[3] == !self.tx[2];
+        if !valid {
+            // Skip to the next byte which could start a command.
+            let n = self.tx.iter().skip(1).position(|b| *b == PACKET_CMD).map_or(self.tx.len(), |i| i + 1);
+            let data = self.tx.drain(..n).collect();
+            self.out.push(Frame::Unknown { host: true, data });
+            return true;
+        }
+
+        let cmd = self.tx[2];
+        let args: Vec<u8> = self.tx.iter().skip(4).copied().collect();
+        let (command, n) = match Command::decode(cmd, &args) {
+            Some(c) => c,
+            None => return false,
+        };
+
+        self.tx.drain(..4 + n);
+        self.expect.extend(command.expects());
+        self.out.push(Frame::Command(command));
+        true
+    }
+
+    fn step_file_info(&mut self) -> bool {
+        let data: Vec<u8> = self.rx.iter().copied().collect();
+        let mut r = ArgReader { data: &data, pos: 0 };
+        let code = StatusCode(r.u8().expect("rx is not empty"));
+
+        let file = if code.is_ok() {
+            let size = r.u32();
+            let _date = r.u16();
+            let _time = r.u16();
+            let _attrib = r.u8();
+            match (size, r.str()) {
+                (Some(size), Some(name)) => Some((size, name)),
+                _ => return false,
+            }
+        } else {
+            None
+        };
+
+        self.rx.drain(..r.pos);
+        self.complete(Frame::FileInfo { code, file });
+        true
+    }
+
+    fn decode_fifo(&mut self) {
+        loop {
+            match MenuCommand::decode(&self.fifo) {
+                Ok(Some((cmd, n))) => {
+                    self.fifo.drain(..n);
+                    if cmd.expected_response() != MenuResponse::None {
+                        self.expect.push_back(Expect::MenuResponse);
+                    }
+                    self.out.push(Frame::Menu(cmd));
+                },
+                Ok(None) => break,
+                Err(_) => {
+                    let data = std::mem::take(&mut self.fifo);
+                    self.out.push(Frame::Unknown { host: true, data });
+                    break;
+                },
+            }
+        }
+    }
+}
+
+/// Decode a whole capture, returning each frame with the time of the event
+/// which completed it.
+pub fn decode_capture(records: &[Record]) -> Vec<(Duration, Frame)> {
+    let mut decoder = Decoder::new();
+    let mut frames = Vec::new();
+    let mut time = Duration::default();
+    for record in records {
+        time = record.time;
+        frames.extend(decoder.push(&record.event).into_iter().map(|f| (time, f)));
+    }
+    frames.extend(decoder.finish().into_iter().map(|f| (time, f)));
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(cmd: u8, args: &[u8]) -> Vec<u8> {
+        let mut data = vec![PACKET_CMD, !PACKET_CMD, cmd, !cmd];
+        data.extend_from_slice(args);
+        data
+    }
+
+    fn status() -> Vec<u8> {
+        cmd(CMD_STATUS, &[])
+    }
+
+    #[test]
+    fn get_mode() {
+        let mut d = Decoder::new();
+        assert_eq!(d.push_tx(&cmd(CMD_GET_MODE, &[])), [Frame::Command(Command::GetMode)]);
+        assert_eq!(d.push_rx(&[MODE_SERVICE]), [Frame::Mode(MODE_SERVICE)]);
+        assert!(d.finish().is_empty());
+    }
+
+    #[test]
+    fn flash_write_with_status_command() {
+        // As sent by `write_flash`: the arguments, then each block after its
+        // ack, then a separate status command.
+        let mut d = Decoder::new();
+        let mut frames = d.push_tx(&cmd(CMD_FLA_WR, &[0, 0, 0x10, 0, 0, 0, 0x04, 0x10]));
+        frames.extend(d.push_rx(&[0]));
+        frames.extend(d.push_tx(&[0x55; ACK_BLOCK_SIZE]));
+        frames.extend(d.push_rx(&[0]));
+        frames.extend(d.push_tx(&[0x55; 0x10]));
+        frames.extend(d.push_tx(&status()));
+        frames.extend(d.push_rx(&[0xa5, 0x00]));
+
+        assert_eq!(frames, [
+            Frame::Command(Command::FlashWrite { addr: 0x1000, len: 0x410 }),
+            Frame::Ack(0),
+            Frame::Block(ACK_BLOCK_SIZE),
+            Frame::Ack(0),
+            Frame::Block(0x10),
+            Frame::Command(Command::Status),
+            Frame::Status(0xa500),
+        ]);
+        assert!(d.finish().is_empty());
+    }
+
+    #[test]
+    fn status_after_command() {
+        // A status which follows the command directly is still recognised.
+        let mut d = Decoder::new();
+        let mut frames = d.push_tx(&cmd(CMD_FPG_FLA, &[0, 0x04, 0, 0]));
+        frames.extend(d.push_rx(&[0xa5, 0x00]));
+        frames.extend(d.push_tx(&cmd(CMD_F_FOPN, &[0x01, 0, 2, b'a', b'b'])));
+        frames.extend(d.push_rx(&[0xa5, 0x04]));
+
+        assert_eq!(frames, [
+            Frame::Command(Command::FpgaFlash { addr: 0x40000 }),
+            Frame::Status(0xa500),
+            Frame::Command(Command::FileOpen { mode: 1, path: "ab".to_string() }),
+            Frame::Status(0xa504),
+        ]);
+    }
+
+    #[test]
+    fn rejected_block() {
+        let mut d = Decoder::new();
+        let mut frames = d.push_tx(&cmd(CMD_FPG_USB, &[0, 0, 0x08, 0]));
+        frames.extend(d.push_rx(&[0x01]));
+        frames.extend(d.push_tx(&status()));
+        frames.extend(d.push_rx(&[0xa5, 0x01]));
+
+        assert_eq!(frames, [
+            Frame::Command(Command::FpgaUsb { len: 0x800 }),
+            Frame::Ack(1),
+            Frame::Command(Command::Status),
+            Frame::Status(0xa501),
+        ]);
+    }
+
+    #[test]
+    fn menu_commands() {
+        // As sent by `load_game` once the menu is running.
+        let fifo = Region::Fifo.base();
+        let mut d = Decoder::new();
+        let mut frames = d.push_rx(&[MENU_READY]);
+        let mut args = fifo.to_be_bytes().to_vec();
+        args.extend_from_slice(&[0, 0, 0, 2, 0]);
+        frames.extend(d.push_tx(&cmd(CMD_MEM_WR, &args)));
+        frames.extend(d.push_tx(b"*t"));
+        frames.extend(d.push_rx(b"k"));
+
+        assert_eq!(frames, [
+            Frame::MenuReady,
+            Frame::Command(Command::MemWrite { addr: fifo, len: 2 }),
+            Frame::Menu(MenuCommand::Test),
+            Frame::MenuResponse(b'k'),
+        ]);
+    }
+
+    #[test]
+    fn file_info() {
+        let mut d = Decoder::new();
+        let mut frames = d.push_tx(&cmd(CMD_F_FINFO, &[0, 1, b'a']));
+        frames.extend(d.push_rx(&[0, 0, 0, 0x12, 0x34, 0, 0, 0, 0, 0x20, 0]));
+        frames.extend(d.push_rx(&[1, b'a']));
+        frames.extend(d.push_tx(&cmd(CMD_F_FINFO, &[0, 1, b'b'])));
+        frames.extend(d.push_rx(&[StatusCode::NO_FILE.0]));
+
+        assert_eq!(frames, [
+            Frame::Command(Command::FileInfo { path: "a".to_string() }),
+            Frame::FileInfo { code: StatusCode::OK, file: Some((0x1234, "a".to_string())) },
+            Frame::Command(Command::FileInfo { path: "b".to_string() }),
+            Frame::FileInfo { code: StatusCode::NO_FILE, file: None },
+        ]);
+    }
+
+    #[test]
+    fn garbage_before_command() {
+        let mut d = Decoder::new();
+        let mut data = vec![0x00, PACKET_CMD, 0x12];
+        data.extend(status());
+        let frames = d.push_tx(&data);
+
+        assert_eq!(frames, [
+            Frame::Unknown { host: true, data: vec![0x00] },
+            Frame::Unknown { host: true, data: vec![PACKET_CMD, 0x12] },
+            Frame::Command(Command::Status),
+        ]);
+    }
+}
@@ -0,0 +1,260 @@
+//! The error type returned by the library.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use crate::{Mode, Region, StatusCode, System};
+
+/// An error from the Mega Everdrive Pro, or from communicating with it.
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error, either on the serial port or a host file.
+    Io(io::Error),
+    /// An error from the serial port driver.
+    #[cfg(feature = "serial")]
+    Serial(serialport::Error),
+    /// The device did not respond in time.
+    Timeout,
+    /// A status response did not have the expected header.
+    InvalidStatus(u16),
+    /// The device reported an error status for the previous command.
+    Status {
+        /// A description of the command.
+        op: &'static str,
+        /// The status code returned by the device.
+        code: StatusCode,
+    },
+    /// The device rejected a block of data during a transfer.
+    Transfer(u8),
+    /// The menu responded with something unexpected.
+    MenuResponse {
+        /// The menu command which was sent.
+        command: &'static str,
+        /// The expected response, if there is one specific response.
+        expected: Option<u8>,
+        /// The response which was received.
+        actual: u8,
+    },
+    /// A menu command could not be decoded.
+    InvalidMenuCommand(String),
+    /// A file-system operation on the SD card failed.
+    FileSystem {
+        /// The path of the file.
+        path: String,
+        /// The status code returned by the device.
+        code: StatusCode,
+    },
+    /// Recovering the firmware failed.
+    Recovery {
+        /// The status code returned by the device.
+        code: StatusCode,
+    },
+    /// A memory transfer did not fit within its region.
+    OutOfRange {
+        /// The region, if the transfer was relative to one.
+        region: Option<Region>,
+        /// The address of the transfer, relative to the region if there is
+        /// one.
+        addr: u32,
+        /// The length of the transfer.
+        len: usize,
+    },
+    /// A transfer was too long for its length to be sent to the device.
+    TooLarge(u64),
+    /// A game needs an FPGA core which was not provided.
+    CoreRequired(System),
+    /// An uploaded ROM did not match when read back.
+    VerifyFailed,
+    /// The operation was cancelled.
+    Cancelled,
+    /// The device could not be found again after changing mode.
+    Reconnect {
+        /// The number of times the port was reopened.
+        attempts: usize,
+        /// The total time spent trying.
+        elapsed: Duration,
+        /// Whether the old device was seen to disconnect.
+        disconnected: bool,
+        /// The error from the last attempt.
+        last_error: Option<Box<Error>>,
+    },
+    /// The device responded in a different mode than expected.
+    WrongMode {
+        /// The mode which was requested.
+        expected: Mode,
+        /// The mode the device reported.
+        actual: Mode,
+    },
+    /// The protocol could not be brought back into step with the device.
+    Resync,
+    /// A ROM database could not be parsed.
+    Database {
+        /// The line the error is on, if known.
+        line: Option<usize>,
+        /// A description of the problem.
+        message: String,
+    },
+    /// A capture file could not be parsed.
+    Capture {
+        /// The line the error is on.
+        line: usize,
+        /// A description of the problem.
+        message: String,
+    },
+    /// The host did something different to what a replayed capture
+    /// expected.
+    ReplayDiverged {
+        /// The line of the capture file which was expected next.
+        line: usize,
+        /// A description of the difference.
+        message: String,
+    },
+    /// The device sent data which could not be decoded.
+    InvalidData(String),
+    /// Any other error, such as one from a `SerialFactory`.
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// The result type returned by the library.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Create an error from a message, or any other error type.
+    pub fn other(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+        Error::Other(e.into())
+    }
+
+    /// Get the status code returned by the device, if the error has one.
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            Error::Status { code, .. } => Some(*code),
+            Error::FileSystem { code, .. } => Some(*code),
+            Error::Recovery { code } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Returns true if a file or directory on the SD card was not found.
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Error::FileSystem { code, .. } if code.is_not_found())
+    }
+
+    /// Returns true if this error was caused by a timeout.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Error::Timeout)
+    }
+
+    /// Returns true if this error means the host and device may disagree
+    /// about where they are in the protocol.
+    pub fn is_desync(&self) -> bool {
+        matches!(self, Error::Timeout | Error::InvalidStatus(_) | Error::InvalidData(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            #[cfg(feature = "serial")]
+            Error::Serial(e) => write!(f, "serial port error: {}", e),
+            Error::Timeout => write!(f, "timed out waiting for device"),
+            Error::InvalidStatus(v) => write!(f, "invalid status response: {:04x}", v),
+            Error::Status { op, code } => write!(f, "{} failed: {}", op, code),
+            Error::Transfer(code) => write!(f, "error transferring data: {}", code),
+            Error::MenuResponse { command, expected: Some(expected), actual } => {
+                write!(f, "unexpected {} response: {:02x} (expected {:02x})", command, actual, expected)
+            },
+            Error::MenuResponse { command, expected: None, actual } => {
+                write!(f, "unexpected {} response: {:02x}", command, actual)
+            },
+            Error::InvalidMenuCommand(msg) => write!(f, "invalid menu command: {}", msg),
+            Error::FileSystem { path, code } => write!(f, "{} on SD card: {}", path, code),
+            Error::Recovery { code: StatusCode::CORE_MATCHES_RECOVERY } => {
+                write!(f, "current core matches recovery copy")
+            },
+            Error::Recovery { code } => write!(f, "recovery failed: {}", code),
+            Error::OutOfRange { region: Some(region), addr, len } => match region.size() {
+                Some(size) => write!(f, "transfer of {} bytes at {:x} is outside {} region ({} bytes)",
+                                     len, addr, region.lower_name(), size),
+                None => write!(f, "transfer of {} bytes at {:x} to {} must be non-empty and at its start",
+                               len, addr, region.lower_name()),
+            },
+            Error::OutOfRange { region: None, addr, len } => {
+                write!(f, "transfer of {} bytes at {:x} is outside of memory", len, addr)
+            },
+            Error::TooLarge(len) => {
+                write!(f, "transfer of {} bytes is too large (the most is {})", len, u32::MAX)
+            },
+            Error::CoreRequired(system) => write!(f, "{} games need an FPGA core", system.lower_name()),
+            Error::VerifyFailed => write!(f, "ROM verification failed"),
+            Error::Cancelled => write!(f, "operation cancelled"),
+            Error::Reconnect { attempts, elapsed, disconnected, last_error } => {
+                write!(f, "timeout reconnecting to device: {} attempts in {:.1}s", attempts, elapsed.as_secs_f64())?;
+                if !disconnected {
+                    write!(f, ", device never disconnected")?;
+                }
+                if let Some(e) = last_error {
+                    write!(f, ", last error: {}", e)?;
+                }
+                Ok(())
+            },
+            Error::WrongMode { expected, actual } => {
+                write!(f, "device is in {} mode, expected {} mode", actual.lower_name(), expected.lower_name())
+            },
+            Error::Resync => write!(f, "unable to re-synchronise with device"),
+            Error::Database { line: Some(line), message } => write!(f, "line {}: {}", line, message),
+            Error::Database { line: None, message } => write!(f, "{}", message),
+            Error::Capture { line, message } => write!(f, "capture line {}: {}", line, message),
+            Error::ReplayDiverged { line, message } => {
+                write!(f, "replay diverged from capture at line {}: {}", line, message)
+            },
+            Error::InvalidData(msg) => write!(f, "invalid data from device: {}", msg),
+            Error::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            #[cfg(feature = "serial")]
+            Error::Serial(e) => Some(e),
+            Error::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        if e.kind() == io::ErrorKind::TimedOut {
+            return Error::Timeout;
+        }
+
+        // Errors from this library may have been wrapped to pass them through
+        // `Read` or `Write` implementations, so unwrap them again.
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let inner = e.into_inner().expect("checked above");
+            return *inner.downcast::<Error>().expect("checked above");
+        }
+
+        Error::Io(e)
+    }
+}
+
+#[cfg(feature = "serial")]
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Error {
+        Error::Serial(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
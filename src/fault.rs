@@ -0,0 +1,285 @@
+//! Injecting faults into a connection, to exercise error handling.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::decode::{Command, Decoder, Frame};
+use crate::{Result, SerialFactory, Transport};
+
+/// What goes wrong when the host asks the device to change mode.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ModeChangeFault {
+    /// Nothing.
+    #[default]
+    None,
+    /// The command never reaches the device, and the connection stays up.
+    Lose,
+    /// The connection drops before the command reaches the device.
+    Disconnect,
+    /// The command goes through, but this many attempts to reconnect fail.
+    FailOpens(usize),
+}
+
+/// The faults to inject. Chances are between 0 and 1.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// The seed for choosing when faults happen, so runs can be repeated.
+    pub seed: u64,
+    /// The chance of dropping each byte.
+    pub drop: f64,
+    /// The chance of sending each byte twice.
+    pub duplicate: f64,
+    /// The chance of flipping a bit in each byte.
+    pub corrupt: f64,
+    /// The chance of delaying each read or write.
+    pub delay: f64,
+    /// How long delays last.
+    pub delay_time: Duration,
+    /// Make every nth read time out. 0 means never.
+    pub timeout_every: usize,
+    /// Apply byte faults to data written by the host, as well as data read
+    /// from the device.
+    pub writes: bool,
+    /// What goes wrong when changing mode.
+    pub mode_change: ModeChangeFault,
+}
+
+/// A xorshift generator. Faults only need to be repeatable, not random.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Xorshift gets stuck on zero.
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        let x = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        p > 0.0 && x < p
+    }
+}
+
+struct InjectorState {
+    rng: Rng,
+    failing_opens: usize,
+}
+
+/// Injects faults into connections. This can be cloned to share it between
+/// several connections.
+#[derive(Clone)]
+pub struct FaultInjector {
+    faults: Arc<Faults>,
+    state: Arc<Mutex<InjectorState>>,
+}
+
+impl FaultInjector {
+    /// Create an injector for `faults`.
+    pub fn new(faults: Faults) -> FaultInjector {
+        let state = InjectorState { rng: Rng::new(faults.seed), failing_opens: 0 };
+        FaultInjector { faults: Arc::new(faults), state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Call before opening a connection. Fails if opening should fail.
+    pub fn open(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.failing_opens > 0 {
+            state.failing_opens -= 1;
+            let e = io::Error::new(io::ErrorKind::NotFound, "device not present (injected fault)");
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Inject faults into a connection.
+    pub fn wrap<T: Transport>(&self, inner: T) -> FaultTransport<T> {
+        FaultTransport {
+            inner,
+            injector: self.clone(),
+            decoder: Decoder::new(),
+            pending: VecDeque::new(),
+            reads: 0,
+            link: Link::Up,
+        }
+    }
+
+    fn chance(&self, p: f64) -> bool {
+        self.state.lock().unwrap().rng.chance(p)
+    }
+
+    /// Apply the byte faults to `data`.
+    fn mangle(&self, data: &[u8], out: &mut VecDeque<u8>) {
+        let f = &self.faults;
+        let mut state = self.state.lock().unwrap();
+        for &b in data {
+            if state.rng.chance(f.drop) {
+                continue;
+            }
+            let b = if state.rng.chance(f.corrupt) {
+                b ^ (1 << (state.rng.next() % 8))
+            } else {
+                b
+            };
+            out.push_back(b);
+            if state.rng.chance(f.duplicate) {
+                out.push_back(b);
+            }
+        }
+    }
+
+    fn maybe_delay(&self) {
+        if self.chance(self.faults.delay) {
+            thread::sleep(self.faults.delay_time);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Link {
+    Up,
+    /// Writes are thrown away.
+    Lost,
+    /// The connection has dropped.
+    Down,
+}
+
+/// A connection with faults injected, created with `FaultInjector::wrap`.
+pub struct FaultTransport<T> {
+    inner: T,
+    injector: FaultInjector,
+    /// Watches for mode changes. It is given both directions, as the host
+    /// sees them, so that payloads are not mistaken for commands.
+    decoder: Decoder,
+    /// Data read from the device after faults were applied.
+    pending: VecDeque<u8>,
+    reads: usize,
+    link: Link,
+}
+
+impl<T> FaultTransport<T> {
+    /// Get the connection faults are injected into.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Transport> Read for FaultTransport<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.link == Link::Down || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let every = self.injector.faults.timeout_every;
+        self.reads += 1;
+        if every > 0 && self.reads % every == 0 {
+            thread::sleep(self.inner.timeout());
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out (injected fault)"));
+        }
+        self.injector.maybe_delay();
+
+        // Keep reading if every byte was dropped, since returning nothing
+        // would look like the end of the connection.
+        let mut tmp = vec![0; buf.len()];
+        while self.pending.is_empty() {
+            let n = self.inner.read(&mut tmp)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.injector.mangle(&tmp[..n], &mut self.pending);
+        }
+
+        let n = buf.len().min(self.pending.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *dst = src;
+        }
+        self.decoder.push_rx(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: Transport> Write for FaultTransport<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.link {
+            Link::Up => {},
+            Link::Lost => return Ok(buf.len()),
+            Link::Down => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "disconnected (injected fault)")),
+        }
+
+        let mode_change = self.decoder.push_tx(buf).iter().any(|f| {
+            matches!(f, Frame::Command(Command::IoReset) | Frame::Command(Command::RunApp))
+        });
+        if mode_change {
+            match self.injector.faults.mode_change {
+                ModeChangeFault::None => {},
+                ModeChangeFault::Lose => {
+                    self.link = Link::Lost;
+                    return Ok(buf.len());
+                },
+                ModeChangeFault::Disconnect => {
+                    self.link = Link::Down;
+                    return Ok(buf.len());
+                },
+                ModeChangeFault::FailOpens(n) => self.injector.state.lock().unwrap().failing_opens = n,
+            }
+        }
+
+        self.injector.maybe_delay();
+        if self.injector.faults.writes {
+            let mut data = VecDeque::with_capacity(buf.len());
+            self.injector.mangle(buf, &mut data);
+            self.inner.write_all(data.make_contiguous())?;
+        } else {
+            self.inner.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for FaultTransport<T> {
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn mark_command(&mut self, cmd: u8) -> Result<()> {
+        self.inner.mark_command(cmd)
+    }
+}
+
+/// A factory which injects faults into every connection opened by another
+/// factory.
+pub struct FaultFactory<F> {
+    inner: F,
+    injector: FaultInjector,
+}
+
+impl<F: SerialFactory> FaultFactory<F> {
+    /// Inject faults into the connections opened by `inner`.
+    pub fn new(inner: F, injector: FaultInjector) -> FaultFactory<F> {
+        FaultFactory { inner, injector }
+    }
+}
+
+impl<F: SerialFactory> SerialFactory for FaultFactory<F> {
+    type Transport = FaultTransport<F::Transport>;
+
+    fn open(&mut self) -> Result<FaultTransport<F::Transport>> {
+        self.injector.open()?;
+        Ok(self.injector.wrap(self.inner.open()?))
+    }
+}
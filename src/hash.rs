@@ -0,0 +1,251 @@
+//! Small, dependency-free hash implementations used to identify ROM images.
+
+use std::io::{self, Read};
+use byteorder::{ByteOrder, BigEndian};
+
+/// An incremental SHA-1 hasher.
+///
+/// SHA-1 is only used here to give ROM images a stable identity, not for
+/// anything security-sensitive.
+#[derive(Clone)]
+pub struct Sha1 {
+    state: [u32; 5],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Default for Sha1 {
+    fn default() -> Sha1 {
+        Sha1::new()
+    }
+}
+
+impl Sha1 {
+    /// Create a new hasher.
+    pub fn new() -> Sha1 {
+        Sha1 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0],
+            block: [0u8; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    /// Hash a complete buffer in one go.
+    pub fn digest(data: &[u8]) -> [u8; 20] {
+        let mut h = Sha1::new();
+        h.update(data);
+        h.finish()
+    }
+
+    /// Feed more data into the hasher.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+
+        while !data.is_empty() {
+            let n = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+
+            if self.block_len == 64 {
+                let block = self.block;
+                self.process(&block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// Finish hashing and return the digest.
+    pub fn finish(mut self) -> [u8; 20] {
+        let bit_len = self.total_len.wrapping_mul(8);
+
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+
+        let mut len = [0u8; 8];
+        BigEndian::write_u64(&mut len, bit_len);
+        self.update(&len);
+
+        let mut out = [0u8; 20];
+        BigEndian::write_u32_into(&self.state, &mut out);
+        out
+    }
+
+    fn process(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 80];
+        BigEndian::read_u32_into(block, &mut w[..16]);
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, w) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+
+            let t = a.rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e].iter()) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+}
+
+/// Format a digest as lower-case hex.
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// An incremental CRC-32 (IEEE) hasher, as used by ROM databases.
+#[derive(Clone)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+impl Crc32 {
+    /// Create a new hasher.
+    pub fn new() -> Crc32 {
+        Crc32 { crc: !0 }
+    }
+
+    /// Hash a complete buffer in one go.
+    pub fn digest(data: &[u8]) -> u32 {
+        let mut h = Crc32::new();
+        h.update(data);
+        h.finish()
+    }
+
+    /// Feed more data into the hasher.
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            let mut c = (self.crc ^ b as u32) & 0xff;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            }
+            self.crc = c ^ (self.crc >> 8);
+        }
+    }
+
+    /// Finish hashing and return the checksum.
+    pub fn finish(self) -> u32 {
+        !self.crc
+    }
+}
+
+/// A reader which hashes everything that is read through it.
+pub struct HashReader<R> {
+    inner: R,
+    sha1: Sha1,
+    crc32: Crc32,
+    len: u64,
+}
+
+impl<R: Read> HashReader<R> {
+    /// Wrap a reader.
+    pub fn new(inner: R) -> HashReader<R> {
+        HashReader {
+            inner,
+            sha1: Sha1::new(),
+            crc32: Crc32::new(),
+            len: 0,
+        }
+    }
+
+    /// The number of bytes read so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.len
+    }
+
+    /// Finish hashing, returning the SHA-1 and CRC-32 of the data read.
+    pub fn finish(self) -> ([u8; 20], u32) {
+        (self.sha1.finish(), self.crc32.finish())
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.sha1.update(&buf[..n]);
+        self.crc32.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha1_hex(data: &[u8]) -> String {
+        to_hex(&Sha1::digest(data))
+    }
+
+    #[test]
+    fn sha1_known_answers() {
+        // FIPS 180-2 appendix A, plus the empty message.
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(sha1_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+                   "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        assert_eq!(sha1_hex(&vec![b'a'; 1_000_000]), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+    }
+
+    #[test]
+    fn sha1_padding_boundaries() {
+        // Messages around the 55/56/64 byte points exercise the padding.
+        assert_eq!(sha1_hex(&[b'a'; 55]), "c1c8bbdc22796e28c0e15163d20899b65621d65a");
+        assert_eq!(sha1_hex(&[b'a'; 56]), "c2db330f6083854c99d4b5bfb6e8f29f201be699");
+        assert_eq!(sha1_hex(&[b'a'; 64]), "0098ba824b5c16427bd7a1122a5a442a25ec644d");
+    }
+
+    #[test]
+    fn sha1_incremental() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let mut h = Sha1::new();
+        for chunk in data.chunks(13) {
+            h.update(chunk);
+        }
+        assert_eq!(h.finish(), Sha1::digest(&data));
+    }
+
+    #[test]
+    fn crc32_known_answers() {
+        assert_eq!(Crc32::digest(b""), 0);
+        assert_eq!(Crc32::digest(b"123456789"), 0xcbf43926);
+        assert_eq!(Crc32::digest(b"The quick brown fox jumps over the lazy dog"), 0x414fa339);
+    }
+
+    #[test]
+    fn hash_reader() {
+        let mut reader = HashReader::new(&b"abc"[..]);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(reader.bytes_read(), 3);
+        let (sha1, crc32) = reader.finish();
+        assert_eq!(to_hex(&sha1), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(crc32, 0x352441c2);
+    }
+}
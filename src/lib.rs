@@ -6,28 +6,77 @@
 //!   https://github.com/krikzz/MEGA-PRO
 //!
 
-use std::time::Duration;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
 use byteorder::{ByteOrder, BigEndian};
-use serialport::SerialPort;
-use anyhow::anyhow;
-use log::{info, debug};
+use log::{info, debug, warn};
+
+mod builder;
+mod cancel;
+pub mod capture;
+mod cursor;
+pub mod decode;
+mod error;
+pub mod fault;
+mod hash;
+mod menu;
+mod progress;
+#[cfg(unix)]
+pub mod pty;
+mod reconnect;
+pub mod rom;
+pub mod romdb;
+mod save;
+pub mod serve;
+pub mod sim;
+mod status;
+mod transport;
+
+pub use builder::{EverdriveBuilder, Timeouts};
+pub use cancel::CancelToken;
+pub use cursor::MemoryCursor;
+pub use error::{Error, Result};
+pub use menu::{MenuCommand, MenuResponse};
+pub use progress::{Phase, Progress, ProgressEvent};
+pub use reconnect::ReconnectPolicy;
+pub use rom::System;
+pub use save::SaveSync;
+pub use status::StatusCode;
+pub use serve::TcpFactory;
+pub use transport::{pipe, PipeTransport, TcpTransport, Transport};
 
 // These constants are from the original megalink.
-const PACKET_CMD: u8 = '+' as u8;
+const PACKET_CMD: u8 = b'+';
 
 const ACK_BLOCK_SIZE: usize = 1024;
+const TRANSFER_CHUNK_SIZE: usize = 0x4000;
+const RESYNC_ATTEMPTS: usize = 8;
+
+/// The byte sent in response to `CMD_GET_MODE` in service mode. Any other
+/// value means app mode.
+const MODE_SERVICE: u8 = 0xa1;
+
+/// The byte sent in place of the rest of a memory write after it is
+/// cancelled.
+const CANCEL_FILL: u8 = 0xff;
+
+/// The byte SRAM is filled with when a game has no stored save.
+const SRAM_BLANK: u8 = 0xff;
 //const MAX_ROM_SIZE: usize = 0xF80000;
 
 const ADDR_ROM: u32 = 0x0000000;
-//const ADDR_SRAM: u32 = 0x1000000;
-//const ADDR_BRAM: u32 = 0x1080000;
-//const ADDR_CFG: u32 = 0x1800000;
-//const ADDR_SSR: u32 = 0x1802000;
+const ADDR_SRAM: u32 = 0x1000000;
+const ADDR_BRAM: u32 = 0x1080000;
+const ADDR_CFG: u32 = 0x1800000;
+const ADDR_SSR: u32 = 0x1802000;
 const ADDR_FIFO: u32 = 0x1810000;
 
-//const SIZE_ROMX: u32 = 0x1000000;
-//const SIZE_SRAM: u32 = 0x80000;
-//const SIZE_BRAM: u32 = 0x80000;
+const SIZE_ROMX: u32 = 0x1000000;
+const SIZE_SRAM: u32 = 0x80000;
+const SIZE_BRAM: u32 = 0x80000;
+const SIZE_CFG: u32 = ADDR_SSR - ADDR_CFG;
+const SIZE_SSR: u32 = ADDR_FIFO - ADDR_SSR;
 
 //const ADDR_FLA_MENU: u32 = 0x00000;
 //const ADDR_FLA_FPGA: u32 = 0x40000;
@@ -92,6 +141,34 @@ const CMD_F_FINFO: u8 = 0xD0;
 const CMD_USB_RECOV: u8 = 0xF0;
 const CMD_RUN_APP: u8 = 0xF1;
 
+/// Get the name of a command, for debug printing.
+pub(crate) fn command_name(cmd: u8) -> Option<&'static str> {
+    let name = match cmd {
+        CMD_STATUS => "status",
+        CMD_GET_MODE => "get-mode",
+        CMD_IO_RST => "io-reset",
+        CMD_FLA_RD => "flash-read",
+        CMD_FLA_WR => "flash-write",
+        CMD_MEM_RD => "mem-read",
+        CMD_MEM_WR => "mem-write",
+        CMD_FPG_USB => "fpga-usb",
+        CMD_FPG_SDC => "fpga-sd",
+        CMD_FPG_FLA => "fpga-flash",
+        CMD_HOST_RST => "host-reset",
+        CMD_F_FOPN => "file-open",
+        CMD_F_FINFO => "file-info",
+        CMD_USB_RECOV => "usb-recover",
+        CMD_RUN_APP => "run-app",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Get the length of a transfer as it is sent to the device.
+fn transfer_len(len: u64) -> Result<u32> {
+    u32::try_from(len).map_err(|_| Error::TooLarge(len))
+}
+
 /// The operation mode of the Mega Everdrive Pro.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Mode {
@@ -123,7 +200,7 @@ pub enum ResetMode {
 }
 
 impl ResetMode {
-    fn command(self) -> u8 {
+    pub(crate) fn command(self) -> u8 {
         match self {
             ResetMode::Off => 0,
             ResetMode::Soft => 1,
@@ -132,6 +209,173 @@ impl ResetMode {
     }
 }
 
+/// A region of the Mega Everdrive Pro's memory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Region {
+    /// The cartridge ROM.
+    Rom,
+    /// The cartridge save RAM.
+    Sram,
+    /// The backup RAM used by the Mega CD.
+    Bram,
+    /// The cartridge configuration.
+    Config,
+    /// The SSF mapper registers.
+    Ssr,
+    /// The FIFO used to communicate with the menu.
+    Fifo,
+}
+
+impl Region {
+    /// All memory regions, in address order.
+    pub const ALL: [Region; 6] = [
+        Region::Rom,
+        Region::Sram,
+        Region::Bram,
+        Region::Config,
+        Region::Ssr,
+        Region::Fifo,
+    ];
+
+    /// Get the lower-case name, used for debug printing.
+    pub fn lower_name(self) -> &'static str {
+        match self {
+            Region::Rom => "rom",
+            Region::Sram => "sram",
+            Region::Bram => "bram",
+            Region::Config => "config",
+            Region::Ssr => "ssr",
+            Region::Fifo => "fifo",
+        }
+    }
+
+    /// Get the address of the start of the region.
+    pub fn base(self) -> u32 {
+        match self {
+            Region::Rom => ADDR_ROM,
+            Region::Sram => ADDR_SRAM,
+            Region::Bram => ADDR_BRAM,
+            Region::Config => ADDR_CFG,
+            Region::Ssr => ADDR_SSR,
+            Region::Fifo => ADDR_FIFO,
+        }
+    }
+
+    /// Get the size of the region, in bytes. The FIFO is a port rather than
+    /// memory, so it has no size, and is only accessed at its base address.
+    pub fn size(self) -> Option<u32> {
+        match self {
+            Region::Rom => Some(SIZE_ROMX),
+            Region::Sram => Some(SIZE_SRAM),
+            Region::Bram => Some(SIZE_BRAM),
+            Region::Config => Some(SIZE_CFG),
+            Region::Ssr => Some(SIZE_SSR),
+            Region::Fifo => None,
+        }
+    }
+
+    /// Find the region which contains a whole transfer.
+    pub fn containing(addr: u32, len: usize) -> Option<Region> {
+        Region::ALL.iter().copied().find(|r| {
+            addr.checked_sub(r.base()).is_some_and(|offset| r.check(offset, len).is_ok())
+        })
+    }
+
+    /// Check that a transfer fits within the region, and return the address
+    /// it starts at. Transfers to the FIFO must be non-empty and start at
+    /// its base address.
+    pub fn check(self, offset: u32, len: usize) -> Result<u32> {
+        let end = offset as u64 + len as u64;
+        let fits = match self.size() {
+            Some(size) => end <= size as u64,
+            None => offset == 0 && len > 0,
+        };
+        let out_of_range = || Error::OutOfRange {
+            region: Some(self),
+            addr: offset,
+            len,
+        };
+        if !fits {
+            return Err(out_of_range());
+        }
+        self.base().checked_add(offset).ok_or_else(out_of_range)
+    }
+}
+
+/// The path of a game, as reported to the menu.
+///
+/// The menu derives the location of the game's save files from this path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GamePath {
+    /// A game uploaded over USB, with the given file name.
+    Usb(String),
+    /// A game stored on the SD card at the given path.
+    Sd(String),
+}
+
+impl GamePath {
+    /// Get the path string sent to the menu.
+    pub fn menu_path(&self) -> String {
+        match self {
+            GamePath::Usb(name) => format!("USB:{}", name),
+            GamePath::Sd(path) => path.clone(),
+        }
+    }
+
+    /// Get the file name component of the path.
+    pub fn file_name(&self) -> &str {
+        match self {
+            GamePath::Usb(name) => name,
+            GamePath::Sd(path) => path.rsplit('/').next().unwrap_or(path),
+        }
+    }
+}
+
+/// The game information sent to the menu when booting a game.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GameInfo {
+    /// The path of the game.
+    pub path: GamePath,
+    /// Keep the currently loaded FPGA core, rather than letting the menu
+    /// load the default one.
+    pub skip_fpga: bool,
+    /// Any other menu commands to send before the game is started, in
+    /// order. This allows game information which has no field here to be
+    /// passed to the menu.
+    pub commands: Vec<MenuCommand>,
+}
+
+impl GameInfo {
+    /// Create the game information for a game uploaded over USB.
+    pub fn usb(name: &str) -> GameInfo {
+        GameInfo {
+            path: GamePath::Usb(name.to_string()),
+            skip_fpga: false,
+            commands: Vec::new(),
+        }
+    }
+
+    /// Create the game information for a game on the SD card.
+    pub fn sd(path: &str) -> GameInfo {
+        GameInfo {
+            path: GamePath::Sd(path.to_string()),
+            skip_fpga: false,
+            commands: Vec::new(),
+        }
+    }
+}
+
+/// Where to load an FPGA core from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FpgaSource {
+    /// A core image held on the host.
+    Data(Vec<u8>),
+    /// A core image on the SD card, at the given path.
+    Sd(String),
+    /// A core image in flash, at the given address.
+    Flash(u32),
+}
+
 /// File metadata for files on the SD card.
 pub struct FileMetadata {
     pub name: String,
@@ -145,52 +389,221 @@ pub struct FileMetadata {
 /// megalink uses. Since the link needs to be re-established after a connect,
 /// picking a specific serial device is not always possible.
 pub trait SerialFactory {
-    fn open(&mut self) -> anyhow::Result<Box<dyn SerialPort>>;
+    /// The type of connection which is opened.
+    type Transport: Transport;
+
+    fn open(&mut self) -> Result<Self::Transport>;
 }
 
 /// The driver for the Mega Everdrive Pro serial interface.
-pub struct EverdriveSerial<F> {
+pub struct EverdriveSerial<F: SerialFactory> {
     factory: F,
-    serial: Box<dyn SerialPort>,
+    serial: F::Transport,
+    save_sync: Option<SaveSync>,
+    verify: bool,
+    progress: Option<Box<dyn Progress>>,
+    tracker: Option<progress::Tracker>,
+    cancel: Option<CancelToken>,
+    reconnect: ReconnectPolicy,
+    timeouts: Timeouts,
+    retries: usize,
 }
 
 impl<F: SerialFactory> EverdriveSerial<F> {
-    fn open_serial(f: &mut F) -> anyhow::Result<Box<dyn SerialPort>> {
+    fn open_serial(f: &mut F, timeouts: &Timeouts) -> Result<F::Transport> {
         let mut s = f.open()?;
-        s.set_timeout(Duration::from_millis(100))?;
+        s.drain(timeouts.drain)?;
+        s.set_timeout(timeouts.command)?;
+        Ok(s)
+    }
 
-        let mut tmp = [0u8; 1024];
-        loop {
-            let n = match s.read(&mut tmp) {
-                Ok(n) => n,
-                Err(_) => break,
-            };
-            if n <= 0 {
-                break;
-            }
-        }
+    /// Create a new Mega Everdrive Pro controller, with the default
+    /// settings.
+    pub fn new(factory: F) -> Result<EverdriveSerial<F>> {
+        EverdriveSerial::builder(factory).build()
+    }
 
-        s.set_timeout(Duration::from_secs(1))?;
-        Ok(s)
+    /// Create a builder, to configure the controller before connecting.
+    pub fn builder(factory: F) -> EverdriveBuilder<F> {
+        EverdriveBuilder::new(factory)
     }
 
-    /// Create a new Mega Everdrive Pro controller.
-    pub fn new(mut factory: F) -> anyhow::Result<EverdriveSerial<F>> {
-        let serial = EverdriveSerial::open_serial(&mut factory)?;
+    fn connect(mut factory: F, timeouts: Timeouts, retries: usize, probe: bool, reconnect: ReconnectPolicy) -> Result<EverdriveSerial<F>> {
+        let serial = EverdriveSerial::open_serial(&mut factory, &timeouts)?;
         let mut s = EverdriveSerial {
             factory,
             serial,
+            save_sync: None,
+            verify: false,
+            progress: None,
+            tracker: None,
+            cancel: None,
+            reconnect,
+            timeouts,
+            retries,
         };
 
-        // Do a status check early, so that if we get stuck (from an incorrect
-        // device, or bad state), we get stuck early.
-        s.get_status()?;
-
-        debug!("initial status {}", s.get_status()?);
+        if probe {
+            // Do a status check early, so that if we get stuck (from an
+            // incorrect device, or bad state), we get stuck early.
+            let status = s.get_status()?;
+            debug!("initial status {}", status);
+        }
         Ok(s)
     }
 
-    fn flush_cmd(&mut self) -> anyhow::Result<()> {
+    /// Configure synchronisation of game saves with the host.
+    ///
+    /// Pass `None` to disable it (the default).
+    pub fn set_save_sync(&mut self, save_sync: Option<SaveSync>) {
+        self.save_sync = save_sync;
+    }
+
+    /// Set whether uploaded games are read back and checked.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Set the observer which receives progress updates from bulk
+    /// operations.
+    pub fn set_progress(&mut self, progress: Option<Box<dyn Progress>>) {
+        self.progress = progress;
+    }
+
+    /// Set the token used to cancel bulk operations.
+    pub fn set_cancel_token(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+
+    /// Set how to reconnect after the device re-enumerates, such as when
+    /// changing mode.
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect = policy;
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|c| c.is_cancelled())
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Bring the protocol back into a known state after a command was
+    /// cancelled part way through, and return the error for the caller.
+    fn finish_cancel(&mut self) -> Error {
+        info!("cancelled, re-synchronising with device");
+
+        if let Err(e) = self.resync() {
+            warn!("failed to re-synchronise after cancel: {}", e);
+        }
+
+        Error::Cancelled
+    }
+
+    /// Bring the protocol back into a known state, after a timeout or a
+    /// response which could not be decoded.
+    ///
+    /// This drains anything the device is still sending, then sends status
+    /// probes until one gets a valid reply. The status of the previous
+    /// command is lost.
+    pub fn resync(&mut self) -> Result<()> {
+        for attempt in 1..=RESYNC_ATTEMPTS {
+            self.serial.drain(self.timeouts.drain)?;
+
+            match self.read_status() {
+                Ok(status) => {
+                    debug!("re-synchronised after {} attempts, status {}", attempt, status);
+                    return Ok(());
+                },
+                Err(e) if e.is_desync() => debug!("resync attempt {} failed: {}", attempt, e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(Error::Resync)
+    }
+
+    /// Run a command, and re-synchronise with the device if the protocol
+    /// got out of step.
+    ///
+    /// If `retry` is set the command must be idempotent, and it is tried
+    /// again up to the configured number of retries. Otherwise the original
+    /// error is returned, but the session is usable again.
+    fn with_resync<T>(&mut self, retry: bool, mut f: impl FnMut(&mut Self) -> Result<T>) -> Result<T> {
+        let retries = if retry { self.retries } else { 0 };
+        let mut attempt = 0;
+        loop {
+            match f(self) {
+                Err(e) if self.resync_after(&e)? && attempt < retries => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// Re-synchronise if an error means the protocol got out of step.
+    ///
+    /// Returns true if a resync was needed.
+    fn resync_after(&mut self, e: &Error) -> Result<bool> {
+        if !e.is_desync() || self.is_cancelled() {
+            return Ok(false);
+        }
+
+        warn!("{}, re-synchronising with device", e);
+        self.resync()?;
+        Ok(true)
+    }
+
+    /// Run an operation, reporting its progress as a single phase.
+    ///
+    /// If another operation is already being reported, the progress of this
+    /// one is counted as part of it. If the operation leaves the protocol
+    /// out of step, this re-synchronises with the device.
+    fn with_progress<T>(&mut self, phase: Phase, total: u64, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let started = self.tracker.is_none();
+        if started {
+            self.tracker = Some(progress::Tracker::new(phase, total));
+        }
+
+        let mut result = f(self);
+
+        if started {
+            if let Err(e) = &result {
+                if let Err(e) = self.resync_after(e) {
+                    result = Err(e);
+                }
+            }
+
+            if let (Some(tracker), Some(progress)) = (self.tracker.take(), self.progress.as_mut()) {
+                progress.finish(&tracker.event());
+            }
+        }
+        result
+    }
+
+    fn progress_add(&mut self, n: usize) {
+        if let Some(tracker) = self.tracker.as_mut() {
+            tracker.add(n as u64);
+            if let Some(progress) = self.progress.as_mut() {
+                progress.update(&tracker.event());
+            }
+        }
+    }
+
+    /// Report a phase which is a single step, once it is done.
+    fn progress_step(&mut self, phase: Phase) {
+        let mut tracker = progress::Tracker::new(phase, 1);
+        tracker.add(1);
+        if let Some(progress) = self.progress.as_mut() {
+            progress.update(&tracker.event());
+            progress.finish(&tracker.event());
+        }
+    }
+
+    fn flush_cmd(&mut self) -> Result<()> {
         debug!("flush cmd");
         self.serial.flush()?;
         // This _really_ should not be needed.... but it is.
@@ -198,60 +611,86 @@ impl<F: SerialFactory> EverdriveSerial<F> {
         Ok(())
     }
 
-    fn tx_cmd(&mut self, cmd: u8) -> anyhow::Result<()> {
-        debug!("tx cmd {:02x}", cmd);
+    fn tx_cmd(&mut self, cmd: u8) -> Result<()> {
+        debug!("tx cmd {:02x} ({})", cmd, command_name(cmd).unwrap_or("unknown"));
         let data = [
             PACKET_CMD,
             !PACKET_CMD,
             cmd,
             !cmd];
 
+        self.serial.mark_command(cmd)?;
         self.serial.write_all(&data)?;
         debug!("tx done");
         Ok(())
     }
 
-    fn tx_u8(&mut self, v: u8) -> anyhow::Result<()> {
+    fn tx_u8(&mut self, v: u8) -> Result<()> {
         let buf = [v];
         self.serial.write_all(&buf)?;
         Ok(())
     }
 
-    fn tx_u16(&mut self, v: u16) -> anyhow::Result<()> {
+    fn tx_u16(&mut self, v: u16) -> Result<()> {
         let mut buf = [0u8; 2];
         BigEndian::write_u16(&mut buf, v);
         self.serial.write_all(&buf)?;
         Ok(())
     }
 
-    fn tx_u32(&mut self, v: u32) -> anyhow::Result<()> {
+    fn tx_u32(&mut self, v: u32) -> Result<()> {
         let mut buf = [0u8; 4];
         BigEndian::write_u32(&mut buf, v);
         self.serial.write_all(&buf)?;
         Ok(())
     }
 
-    fn tx_ack(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        for chunk in data.chunks(ACK_BLOCK_SIZE) {
+    /// Send data in blocks, each of which the device acknowledges first.
+    ///
+    /// This is used for flash writes and FPGA loads, so it is never cut
+    /// short by cancellation: callers check for it before starting.
+    fn tx_ack(&mut self, mut data: impl Read, len: u64) -> Result<()> {
+        let mut chunk = [0u8; ACK_BLOCK_SIZE];
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(ACK_BLOCK_SIZE as u64) as usize;
+            remaining -= n as u64;
+            data.read_exact(&mut chunk[..n])?;
+
             let resp = self.rx_u8()?;
             if resp != 0 {
-                Err(anyhow!("error transferring data: {}", resp))?;
+                // It is not known how much more the device expects, so get
+                // back into step before reporting the error.
+                if let Err(e) = self.resync() {
+                    warn!("failed to re-synchronise after transfer error: {}", e);
+                }
+                return Err(Error::Transfer(resp));
             }
 
-            self.serial.write_all(chunk)?;
+            self.serial.write_all(&chunk[..n])?;
             self.flush_cmd()?;
+            self.progress_add(n);
         }
+        Ok(())
+    }
 
+    fn tx_fill(&mut self, mut len: usize) -> Result<()> {
+        let chunk = [CANCEL_FILL; TRANSFER_CHUNK_SIZE];
+        while len > 0 {
+            let n = len.min(chunk.len());
+            self.serial.write_all(&chunk[..n])?;
+            len -= n;
+        }
         Ok(())
     }
 
-    fn tx_str(&mut self, s: &str) -> anyhow::Result<()> {
+    fn tx_str(&mut self, s: &str) -> Result<()> {
         self.tx_u16(s.len() as u16)?;
         self.serial.write_all(s.as_bytes())?;
         Ok(())
     }
 
-    fn rx_u8(&mut self) -> anyhow::Result<u8> {
+    fn rx_u8(&mut self) -> Result<u8> {
         debug!("rx 8");
         let mut v = [0u8; 1];
         self.serial.read_exact(&mut v)?;
@@ -259,7 +698,7 @@ impl<F: SerialFactory> EverdriveSerial<F> {
         Ok(v[0])
     }
 
-    fn rx_u16(&mut self) -> anyhow::Result<u16> {
+    fn rx_u16(&mut self) -> Result<u16> {
         debug!("rx 16");
         let mut bytes = [0u8; 2];
         self.serial.read_exact(&mut bytes)?;
@@ -267,7 +706,7 @@ impl<F: SerialFactory> EverdriveSerial<F> {
         Ok(BigEndian::read_u16(&bytes))
     }
 
-    fn rx_u32(&mut self) -> anyhow::Result<u32> {
+    fn rx_u32(&mut self) -> Result<u32> {
         debug!("rx 32");
         let mut bytes = [0u8; 4];
         self.serial.read_exact(&mut bytes)?;
@@ -275,14 +714,33 @@ impl<F: SerialFactory> EverdriveSerial<F> {
         Ok(BigEndian::read_u32(&bytes))
     }
 
-    fn rx_str(&mut self) -> anyhow::Result<String> {
+    fn rx_str(&mut self) -> Result<String> {
         let len = self.rx_u16()? as usize;
         let mut bytes = vec![0u8; len];
         self.serial.read_exact(&mut bytes)?;
-        Ok(String::from_utf8(bytes)?)
+        String::from_utf8(bytes)
+            .map_err(|e| Error::InvalidData(format!("invalid string: {}", e)))
     }
 
-    fn rx_file_metadata(&mut self) -> anyhow::Result<FileMetadata> {
+    fn rx_chunked(&mut self, data: &mut [u8]) -> Result<()> {
+        let mut cancelled = false;
+        for chunk in data.chunks_mut(TRANSFER_CHUNK_SIZE) {
+            // Once cancelled, the rest of the data still needs to be read,
+            // but it is discarded.
+            cancelled = cancelled || self.is_cancelled();
+            self.serial.read_exact(chunk)?;
+            if !cancelled {
+                self.progress_add(chunk.len());
+            }
+        }
+
+        if cancelled {
+            return Err(self.finish_cancel());
+        }
+        Ok(())
+    }
+
+    fn rx_file_metadata(&mut self) -> Result<FileMetadata> {
         let size = self.rx_u32()?;
         let date = self.rx_u16()?;
         let time = self.rx_u16()?;
@@ -300,42 +758,51 @@ impl<F: SerialFactory> EverdriveSerial<F> {
 
     /// Get the return code of the previous operation.
     ///
-    /// This is cleared once read. 0 indicates success.
-    pub fn get_status(&mut self) -> anyhow::Result<u8> {
+    /// This is cleared once read. If the reply can't be decoded, the
+    /// protocol is re-synchronised and the error returned: the command is
+    /// not retried, since the status is lost by then.
+    pub fn get_status(&mut self) -> Result<StatusCode> {
+        self.with_resync(false, |s| s.read_status())
+    }
+
+    fn read_status(&mut self) -> Result<StatusCode> {
         self.tx_cmd(CMD_STATUS)?;
         self.flush_cmd()?;
         let msg = self.rx_u16()?;
 
         if (msg & 0xff00) != 0xa500 {
-            Err(anyhow!("invalid status response: {:04x}", msg))?;
+            return Err(Error::InvalidStatus(msg));
         }
 
-        Ok(msg as u8)
+        Ok(StatusCode(msg as u8))
     }
 
-    fn check_status(&mut self) -> anyhow::Result<()> {
-        let res = self.get_status()?;
-        if res != 0 {
-            Err(anyhow!("unexpected status {}", res))?;
+    /// Check the status of the previous operation, described by `op`.
+    fn check_status(&mut self, op: &'static str) -> Result<()> {
+        let code = self.get_status()?;
+        if !code.is_ok() {
+            return Err(Error::Status { op, code });
         }
         Ok(())
     }
 
     /// Get the current operating mode of the cartridge.
-    pub fn get_mode(&mut self) -> anyhow::Result<Mode> {
-        self.tx_cmd(CMD_GET_MODE)?;
-        self.flush_cmd()?;
-
-        let b = self.rx_u8()?;
-        let mode = match b {
-            0xa1 => Mode::Service,
-            _ => Mode::App,
-        };
-        Ok(mode)
+    pub fn get_mode(&mut self) -> Result<Mode> {
+        self.with_resync(true, |s| {
+            s.tx_cmd(CMD_GET_MODE)?;
+            s.flush_cmd()?;
+
+            let b = s.rx_u8()?;
+            let mode = match b {
+                MODE_SERVICE => Mode::Service,
+                _ => Mode::App,
+            };
+            Ok(mode)
+        })
     }
 
     /// Change the current operating mode.
-    pub fn set_mode(&mut self, target_mode: Mode) -> anyhow::Result<()> {
+    pub fn set_mode(&mut self, target_mode: Mode) -> Result<()> {
         let current_mode = self.get_mode()?;
         if current_mode == target_mode {
             return Ok(());
@@ -353,25 +820,89 @@ impl<F: SerialFactory> EverdriveSerial<F> {
             }
         }
 
-        for _ in 0..100 {
-            let serial = match EverdriveSerial::open_serial(&mut self.factory) {
-                Ok(s) => s,
+        self.flush_cmd()?;
+        self.reconnect(target_mode)
+    }
+
+    /// Reconnect after the device re-enumerates, and check that it came back
+    /// in the expected mode.
+    fn reconnect(&mut self, mode: Mode) -> Result<()> {
+        let policy = self.reconnect.clone();
+        let start = Instant::now();
+
+        let disconnected = self.wait_disconnect(policy.disconnect_timeout);
+        if disconnected {
+            debug!("device disconnected after {:?}", start.elapsed());
+        } else {
+            debug!("device did not disconnect, reconnecting anyway");
+        }
+
+        let mut delay = policy.initial_delay;
+        let mut attempts = 0;
+        let mut last_error = None;
+        while start.elapsed() < policy.timeout {
+            self.check_cancelled()?;
+            std::thread::sleep(delay.min(policy.timeout.saturating_sub(start.elapsed())));
+            delay = policy.next_delay(delay);
+            attempts += 1;
+
+            match self.try_reconnect(mode) {
+                Ok(()) => {
+                    debug!("reconnected after {} attempts in {:?}", attempts, start.elapsed());
+                    return Ok(());
+                },
                 Err(e) => {
-                    debug!("error waiting for reset: {}", e);
-                    continue;
-                }
-            };
-            self.get_status()?;
+                    debug!("reconnect attempt {} failed: {}", attempts, e);
+                    last_error = Some(Box::new(e));
+                },
+            }
+        }
 
-            self.serial = serial;
-            return Ok(());
+        Err(Error::Reconnect {
+            attempts,
+            elapsed: start.elapsed(),
+            disconnected,
+            last_error,
+        })
+    }
+
+    /// Wait for the current port to report that the device has gone.
+    ///
+    /// Returns false if it is still there after `timeout`.
+    fn wait_disconnect(&mut self, timeout: Duration) -> bool {
+        // The port is replaced after reconnecting, so the timeout does not
+        // need restoring.
+        if self.serial.set_timeout(Duration::from_millis(50)).is_err() {
+            return true;
+        }
+
+        let start = Instant::now();
+        let mut tmp = [0u8; 64];
+        while start.elapsed() < timeout {
+            match self.serial.read(&mut tmp) {
+                Ok(0) => return true,
+                Ok(_) => {},
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {},
+                Err(_) => return true,
+            }
         }
+        false
+    }
+
+    /// Open the port again, and check the device responds in `mode`.
+    fn try_reconnect(&mut self, mode: Mode) -> Result<()> {
+        self.serial = EverdriveSerial::open_serial(&mut self.factory, &self.timeouts)?;
+        self.get_status()?;
 
-        Err(anyhow!("timeout reconnecting to device"))?
+        let actual = self.get_mode()?;
+        if actual != mode {
+            return Err(Error::WrongMode { expected: mode, actual });
+        }
+        Ok(())
     }
 
     /// Reset the Mega Drive.
-    pub fn reset_host(&mut self, mode: ResetMode) -> anyhow::Result<()> {
+    pub fn reset_host(&mut self, mode: ResetMode) -> Result<()> {
         self.tx_cmd(CMD_HOST_RST)?;
         self.tx_u8(mode.command())?;
         self.flush_cmd()?;
@@ -380,49 +911,109 @@ impl<F: SerialFactory> EverdriveSerial<F> {
 
     /// Write to the Mega Drive's memory. This can be used to write to the ROM
     /// area with the Mega Everdrive.
-    pub fn write_memory(&mut self, addr: u32, data: &[u8]) -> anyhow::Result<()> {
-        if data.len() == 0 {
+    ///
+    /// The transfer must fit within one memory region.
+    pub fn write_memory(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        if Region::containing(addr, data.len()).is_none() {
+            return Err(Error::OutOfRange { region: None, addr, len: data.len() });
+        }
+        self.write_memory_unchecked(addr, data)
+    }
+
+    /// Write to the Mega Drive's memory, without checking the address.
+    pub fn write_memory_unchecked(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
             return Ok(());
         }
 
         debug!("write {} to {:x}", data.len(), addr);
 
-        self.tx_cmd(CMD_MEM_WR)?;
-        self.tx_u32(addr)?;
-        self.tx_u32(data.len() as u32)?;
-        self.tx_u8(0)?;
-        self.flush_cmd()?;
+        self.check_cancelled()?;
+        self.with_progress(Phase::Upload, data.len() as u64, |s| {
+            s.tx_cmd(CMD_MEM_WR)?;
+            s.tx_u32(addr)?;
+            s.tx_u32(data.len() as u32)?;
+            s.tx_u8(0)?;
+            s.flush_cmd()?;
+
+            let mut sent = 0;
+            for chunk in data.chunks(TRANSFER_CHUNK_SIZE) {
+                if s.is_cancelled() {
+                    break;
+                }
 
-        self.serial.write_all(data)?;
-        self.flush_cmd()?;
-        Ok(())
+                s.serial.write_all(chunk)?;
+                s.progress_add(chunk.len());
+                sent += chunk.len();
+            }
+
+            if sent < data.len() {
+                // The device is still expecting the rest of the data.
+                s.tx_fill(data.len() - sent)?;
+                s.flush_cmd()?;
+                return Err(s.finish_cancel());
+            }
+
+            s.flush_cmd()?;
+            Ok(())
+        })
     }
 
     /// Read from the Mega Drive's memory.
-    pub fn read_memory(&mut self, addr: u32, data: &mut [u8]) -> anyhow::Result<()> {
-        if data.len() == 0 {
+    ///
+    /// The transfer must fit within one memory region.
+    pub fn read_memory(&mut self, addr: u32, data: &mut [u8]) -> Result<()> {
+        if Region::containing(addr, data.len()).is_none() {
+            return Err(Error::OutOfRange { region: None, addr, len: data.len() });
+        }
+        self.read_memory_unchecked(addr, data)
+    }
+
+    /// Read from the Mega Drive's memory, without checking the address.
+    pub fn read_memory_unchecked(&mut self, addr: u32, data: &mut [u8]) -> Result<()> {
+        if data.is_empty() {
             return Ok(());
         }
 
-        self.tx_cmd(CMD_MEM_RD)?;
-        self.tx_u32(addr)?;
-        self.tx_u32(data.len() as u32)?;
-        self.tx_u8(0)?;
-        self.flush_cmd()?;
+        self.check_cancelled()?;
+        self.with_progress(Phase::Download, data.len() as u64, |s| {
+            s.tx_cmd(CMD_MEM_RD)?;
+            s.tx_u32(addr)?;
+            s.tx_u32(data.len() as u32)?;
+            s.tx_u8(0)?;
+            s.flush_cmd()?;
 
-        self.serial.read_exact(data)?;
-        Ok(())
+            s.rx_chunked(data)
+        })
+    }
+
+    /// Write to a memory region, at an offset from its start.
+    pub fn write_region(&mut self, region: Region, offset: u32, data: &[u8]) -> Result<()> {
+        let addr = region.check(offset, data.len())?;
+        self.write_memory_unchecked(addr, data)
+    }
+
+    /// Read from a memory region, at an offset from its start.
+    pub fn read_region(&mut self, region: Region, offset: u32, data: &mut [u8]) -> Result<()> {
+        let addr = region.check(offset, data.len())?;
+        self.read_memory_unchecked(addr, data)
+    }
+
+    /// Get a cursor which can be used to stream data to or from a memory
+    /// region.
+    pub fn cursor(&mut self, region: Region) -> MemoryCursor<'_, F> {
+        MemoryCursor::new(self, region)
     }
 
     /// Write to the FIFO used internally by the Mega Everdrive for communication
     /// with the IO co-processor.
-    pub fn fifo_write(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        self.write_memory(ADDR_FIFO, data)?;
+    pub fn fifo_write(&mut self, data: &[u8]) -> Result<()> {
+        self.write_region(Region::Fifo, 0, data)?;
         Ok(())
     }
 
     /// Write an integer to the FIFO.
-    pub fn fifo_write_u16(&mut self, v: u16) -> anyhow::Result<()> {
+    pub fn fifo_write_u16(&mut self, v: u16) -> Result<()> {
         let mut buf = [0u8; 2];
         BigEndian::write_u16(&mut buf, v);
         self.fifo_write(&buf)?;
@@ -430,7 +1021,7 @@ impl<F: SerialFactory> EverdriveSerial<F> {
     }
 
     /// Write an integer to the FIFO.
-    pub fn fifo_write_u32(&mut self, v: u32) -> anyhow::Result<()> {
+    pub fn fifo_write_u32(&mut self, v: u32) -> Result<()> {
         let mut buf = [0u8; 4];
         BigEndian::write_u32(&mut buf, v);
         self.fifo_write(&buf)?;
@@ -438,95 +1029,314 @@ impl<F: SerialFactory> EverdriveSerial<F> {
     }
 
     /// Write a string to the FIFO.
-    pub fn fifo_write_str(&mut self, str: &str) -> anyhow::Result<()> {
+    pub fn fifo_write_str(&mut self, str: &str) -> Result<()> {
         self.fifo_write_u16(str.len() as u16)?;
         self.fifo_write(str.as_bytes())?;
         Ok(())
     }
 
     /// Read some data from the FIFO.
-    pub fn fifo_read(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
-        self.read_memory(ADDR_FIFO, data)?;
+    pub fn fifo_read(&mut self, data: &mut [u8]) -> Result<()> {
+        self.read_region(Region::Fifo, 0, data)?;
         Ok(())
     }
 
     /// Read from flash storage.
-    pub fn read_flash(&mut self, addr: u32, data: &mut [u8]) -> anyhow::Result<()> {
-        self.tx_cmd(CMD_FLA_RD)?;
-        self.tx_u32(addr)?;
-        self.tx_u32(data.len() as u32)?;
-        self.flush_cmd()?;
-
-        self.serial.read_exact(data)?;
-        Ok(())
+    pub fn read_flash(&mut self, addr: u32, data: &mut [u8]) -> Result<()> {
+        self.check_cancelled()?;
+        self.with_progress(Phase::Download, data.len() as u64, |s| {
+            s.tx_cmd(CMD_FLA_RD)?;
+            s.tx_u32(addr)?;
+            s.tx_u32(data.len() as u32)?;
+            s.flush_cmd()?;
+
+            s.rx_chunked(data)
+        })
     }
 
     /// Write to flash storage.
-    pub fn write_flash(&mut self, addr: u32, data: &[u8]) -> anyhow::Result<()> {
-        self.tx_cmd(CMD_FLA_WR)?;
-        self.tx_u32(addr)?;
-        self.tx_u32(data.len() as u32)?;
-        self.flush_cmd()?;
-        self.tx_ack(data)?;
-        self.check_status()?;
-        Ok(())
+    pub fn write_flash(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        self.write_flash_from_reader(addr, data, data.len() as u64)
+    }
+
+    /// Write `len` bytes from a reader to flash storage.
+    pub fn write_flash_from_reader(&mut self, addr: u32, data: impl Read, len: u64) -> Result<()> {
+        let len32 = transfer_len(len)?;
+        self.check_cancelled()?;
+        self.with_progress(Phase::Upload, len, |s| {
+            s.tx_cmd(CMD_FLA_WR)?;
+            s.tx_u32(addr)?;
+            s.tx_u32(len32)?;
+            s.flush_cmd()?;
+            s.tx_ack(data, len)?;
+            s.check_status("flash write")
+        })
     }
 
     /// Load and boot a game ROM.
-    pub fn load_game(&mut self, name: &str, game: &[u8], skip_fpga: bool) -> anyhow::Result<()> {
-        debug!("writing ROM: {} ({} bytes)", name, game.len());
+    pub fn load_game(&mut self, info: &GameInfo, game: &[u8]) -> Result<()> {
+        self.load_game_from_reader(info, game, game.len() as u64)
+    }
+
+    /// Load and boot a game ROM, streaming `len` bytes of it from a reader.
+    pub fn load_game_from_reader(&mut self, info: &GameInfo, game: impl Read, len: u64) -> Result<()> {
+        let name = info.path.file_name();
+        debug!("writing ROM: {} ({} bytes)", name, len);
+        Region::Rom.check(0, len as usize)?;
         self.set_mode(Mode::App)?;
+        self.store_save()?;
+
         self.reset_host(ResetMode::Soft)?;
-        self.write_memory(ADDR_ROM, game)?;
+
+        let digest = self.with_progress(Phase::Upload, len, |s| {
+            let mut game = hash::HashReader::new(game.take(len));
+            let mut cursor = s.cursor(Region::Rom);
+            io::copy(&mut game, &mut cursor)?;
+            cursor.flush()?;
+            drop(cursor);
+
+            if game.bytes_read() != len {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("ROM ended after {} of {} bytes", game.bytes_read(), len))));
+            }
+            Ok(game.finish().0)
+        })?;
+
+        if self.verify {
+            self.with_progress(Phase::Verify, len, |s| {
+                let mut rom = hash::HashReader::new(s.cursor(Region::Rom).take(len));
+                io::copy(&mut rom, &mut io::sink())?;
+                if rom.finish().0 != digest {
+                    return Err(Error::VerifyFailed);
+                }
+                Ok(())
+            })?;
+        }
+
+        let hash = hash::to_hex(&digest);
+        self.restore_save(&hash, name)?;
         self.reset_host(ResetMode::Off)?;
 
+        self.with_resync(false, |s| {
+            s.wait_menu_ready()?;
+            s.send_menu_command(&MenuCommand::Test)?;
+            s.send_game_info(info, len as u32)
+        })?;
+        self.progress_step(Phase::Boot);
+        Ok(())
+    }
+
+    /// Load and boot a game ROM for any supported system.
+    ///
+    /// Master System and Game Gear games need their FPGA core to be loaded
+    /// first, from `core`. The game is reported to the menu with the file
+    /// extension for the system, so the menu configures the cartridge (such
+    /// as the 32X mapping) correctly.
+    ///
+    /// Nothing else is done for 32X games: no configuration is written, and
+    /// the menu is relied on to detect the system from the `.32x` extension,
+    /// as it does for games launched from its own file browser.
+    pub fn load_system_game(&mut self, system: System, core: Option<&FpgaSource>, info: &GameInfo, game: &[u8]) -> Result<()> {
+        self.load_system_game_from_reader(system, core, info, game, game.len() as u64)
+    }
+
+    /// Load and boot a game ROM for any supported system, streaming `len`
+    /// bytes of it from a reader.
+    pub fn load_system_game_from_reader(&mut self, system: System, core: Option<&FpgaSource>, info: &GameInfo, game: impl Read, len: u64) -> Result<()> {
+        debug!("loading {} game", system.lower_name());
+
+        let mut info = info.clone();
+        if let Some(core) = core {
+            self.load_fpga(core)?;
+            info.skip_fpga = true;
+        } else if system.needs_core() {
+            return Err(Error::CoreRequired(system));
+        }
+
+        if let GamePath::Usb(name) = &mut info.path {
+            if system != System::MegaDrive && System::from_file_name(name) != Some(system) {
+                name.push('.');
+                name.push_str(system.extension());
+            }
+        }
+
+        self.load_game_from_reader(&info, game, len)
+    }
+
+    /// Boot a game ROM which is stored on the SD card, without uploading it.
+    ///
+    /// The menu loads the game itself, so saves are handled as if the game
+    /// were launched from the menu.
+    ///
+    /// This relies on the menu loading the file named by `StartGame` from
+    /// the SD card when the path lacks the `USB:` prefix used for uploaded
+    /// games. That is inferred from the path convention, and has not been
+    /// checked against the menu firmware's source.
+    pub fn load_game_from_sd(&mut self, path: &str, skip_fpga: bool) -> Result<()> {
+        debug!("booting ROM from {}", path);
+        self.set_mode(Mode::App)?;
+
+        let meta = self.get_file_metadata(path)?;
+        self.store_save()?;
+        if let Some(save_sync) = self.save_sync.as_ref() {
+            save_sync.clear_current()?;
+        }
+
+        self.reset_host(ResetMode::Soft)?;
+        self.reset_host(ResetMode::Off)?;
+
+        let mut info = GameInfo::sd(path);
+        info.skip_fpga = skip_fpga;
+
+        self.with_resync(false, |s| {
+            s.wait_menu_ready()?;
+            s.send_menu_command(&MenuCommand::Test)?;
+            s.send_game_info(&info, meta.size)
+        })?;
+        self.progress_step(Phase::Boot);
+        Ok(())
+    }
+
+    /// Tell the menu which game is loaded, which causes it to boot the game.
+    ///
+    /// This sends `SkipFpga` if requested, then `info.commands`, and finally
+    /// `StartGame`.
+    pub fn send_game_info(&mut self, info: &GameInfo, size: u32) -> Result<()> {
+        if info.skip_fpga {
+            self.send_menu_command(&MenuCommand::SkipFpga)?;
+        }
+        for cmd in &info.commands {
+            self.send_menu_command(cmd)?;
+        }
+
+        self.send_menu_command(&MenuCommand::StartGame {
+            size,
+            path: info.path.menu_path(),
+        })?;
+        Ok(())
+    }
+
+    /// Wait for the menu to signal that it has started, after a reset.
+    pub fn wait_menu_ready(&mut self) -> Result<()> {
         let resp = self.rx_u8()?;
-        if resp != 'r' as u8 {
-            Err(anyhow!("unexpected response: {}", resp))?;
+        if resp != menu::MENU_READY {
+            return Err(Error::MenuResponse {
+                command: "ready",
+                expected: Some(menu::MENU_READY),
+                actual: resp,
+            });
         }
+        Ok(())
+    }
 
-        debug!("testing");
-        self.fifo_write("*t".as_bytes())?;
+    /// Send a command to the menu, and check its response.
+    ///
+    /// Returns the response byte, if the command has one.
+    pub fn send_menu_command(&mut self, cmd: &MenuCommand) -> Result<Option<u8>> {
+        debug!("menu command {:?}", cmd);
+        self.fifo_write(&cmd.encode()?)?;
         self.flush_cmd()?;
 
-        let resp = self.rx_u8()?;
-        if resp != 'k' as u8 {
-            Err(anyhow!("unexpected test response: {}", resp))?;
+        if cmd.expected_response() == MenuResponse::None {
+            return Ok(None);
         }
 
-        if skip_fpga {
-            self.fifo_write("*u".as_bytes())?;
-            self.flush_cmd()?;
+        let resp = self.rx_u8()?;
+        cmd.check_response(resp)?;
+        Ok(Some(resp))
+    }
+
+    /// Copy the SRAM of the last uploaded game to the host.
+    fn store_save(&mut self) -> Result<()> {
+        let save_sync = match self.save_sync.clone() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        let hash = match save_sync.current()? {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+
+        let size = save_sync.sram_size.min(SIZE_SRAM as usize);
+        let mut sram = vec![0u8; size];
+        self.read_region(Region::Sram, 0, &mut sram)?;
+        save_sync.store(&hash, &sram)?;
+        Ok(())
+    }
+
+    /// Restore the SRAM of a newly uploaded game from the host.
+    fn restore_save(&mut self, hash: &str, name: &str) -> Result<()> {
+        let save_sync = match self.save_sync.clone() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        // The SRAM still holds the previous game's save, so it must not be
+        // stored under this game's hash later.
+        if !save_sync.restore {
+            save_sync.clear_current()?;
+            return Ok(());
         }
 
-        debug!("setting game info");
-        self.fifo_write("*g".as_bytes())?;
-        self.fifo_write_u32(game.len() as u32)?;
-        self.fifo_write_str(&format!("USB:{}", name))?;
-        self.flush_cmd()?;
+        let sram = match save_sync.load(hash)? {
+            Some(mut sram) => {
+                let size = SIZE_SRAM as usize;
+                if sram.len() > size {
+                    warn!("stored save for {} is too large, truncating", hash);
+                    sram.truncate(size);
+                }
 
-        // Clear response.
-        self.rx_u8()?;
+                info!("restoring save for {}", hash);
+                sram
+            },
+            None => {
+                debug!("no save for {}, clearing SRAM", hash);
+                let size = save_sync.sram_size.min(SIZE_SRAM as usize);
+                vec![SRAM_BLANK; size]
+            },
+        };
+        self.write_region(Region::Sram, 0, &sram)?;
+        save_sync.set_current(hash, name)?;
         Ok(())
     }
 
+    /// Load an image into the FPGA.
+    pub fn load_fpga(&mut self, source: &FpgaSource) -> Result<()> {
+        match source {
+            FpgaSource::Data(data) => self.load_fpga_from_slice(data),
+            FpgaSource::Sd(path) => self.load_fpga_from_sd(path),
+            FpgaSource::Flash(addr) => self.load_fpga_from_flash(*addr),
+        }
+    }
+
     /// Load an image into the FPGA from a slice.
-    pub fn load_fpga_from_slice(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        debug!("loading FPGA image ({} bytes)", data.len());
+    pub fn load_fpga_from_slice(&mut self, data: &[u8]) -> Result<()> {
+        self.load_fpga_from_reader(data, data.len() as u64)
+    }
+
+    /// Load an image into the FPGA, streaming `len` bytes of it from a
+    /// reader.
+    pub fn load_fpga_from_reader(&mut self, data: impl Read, len: u64) -> Result<()> {
+        debug!("loading FPGA image ({} bytes)", len);
+        let len32 = transfer_len(len)?;
 
         self.set_mode(Mode::App)?;
         self.reset_host(ResetMode::Soft)?;
 
-        self.tx_cmd(CMD_FPG_USB)?;
-        self.tx_u32(data.len() as u32)?;
+        self.check_cancelled()?;
+        self.with_progress(Phase::Upload, len, |s| {
+            s.tx_cmd(CMD_FPG_USB)?;
+            s.tx_u32(len32)?;
 
-        self.tx_ack(data)?;
-        self.check_status()?;
-        Ok(())
+            s.tx_ack(data, len)?;
+            s.check_status("FPGA load")
+        })
     }
 
     /// Load an image into the FPGA from flash storage.
-    pub fn load_fpga_from_flash(&mut self, addr: u32) -> anyhow::Result<()> {
+    pub fn load_fpga_from_flash(&mut self, addr: u32) -> Result<()> {
         debug!("loading FPGA image @ {:x}", addr);
 
         self.set_mode(Mode::App)?;
@@ -535,12 +1345,12 @@ impl<F: SerialFactory> EverdriveSerial<F> {
         self.tx_cmd(CMD_FPG_FLA)?;
         self.tx_u32(addr)?;
         self.flush_cmd()?;
-        self.check_status()?;
+        self.check_status("FPGA load from flash")?;
         Ok(())
     }
 
     /// Load an image into the FPGA from the SD card.
-    pub fn load_fpga_from_sd(&mut self, path: &str) -> anyhow::Result<()> {
+    pub fn load_fpga_from_sd(&mut self, path: &str) -> Result<()> {
         debug!("loading FPGA from {}", path);
 
         self.set_mode(Mode::App)?;
@@ -552,12 +1362,12 @@ impl<F: SerialFactory> EverdriveSerial<F> {
         self.tx_u32(info.size)?;
         self.tx_u8(0)?;
         self.flush_cmd()?;
-        self.check_status()?;
+        self.check_status("FPGA load from SD card")?;
         Ok(())
     }
 
     /// Recover from badly-flashed firmware.
-    pub fn recover(&mut self) -> anyhow::Result<()> {
+    pub fn recover(&mut self) -> Result<()> {
         self.set_mode(Mode::Service)?;
 
         let mut crc = [0u8; 4];
@@ -565,44 +1375,48 @@ impl<F: SerialFactory> EverdriveSerial<F> {
         let crc = BigEndian::read_u32(&crc);
 
         let old_timeout = self.serial.timeout();
-        self.serial.set_timeout(Duration::from_secs(8))?;
+        self.serial.set_timeout(self.timeouts.recovery)?;
 
         self.tx_cmd(CMD_USB_RECOV)?;
         self.tx_u32(ADDR_FLA_ICOR)?;
         self.tx_u32(crc)?;
 
-        let status = self.get_status()?;
+        let code = self.get_status()?;
         self.serial.set_timeout(old_timeout)?;
 
-        match status {
-            0x88 => Err(anyhow!("current core matches recovery copy"))?,
-            0 => {},
-            v => Err(anyhow!("recovery error: {:2x}", v))?,
+        if !code.is_ok() {
+            return Err(Error::Recovery { code });
         }
 
         Ok(())
     }
 
     /// Fetch the metadata for a file on the SD card.
-    pub fn get_file_metadata(&mut self, path: &str) -> anyhow::Result<FileMetadata> {
-        self.tx_cmd(CMD_F_FINFO)?;
-        self.tx_str(path)?;
-        self.flush_cmd()?;
-
-        let resp = self.rx_u8()?;
-        if resp != 0 {
-            Err(anyhow!("error fetching file metadata: {}", resp))?;
-        }
+    pub fn get_file_metadata(&mut self, path: &str) -> Result<FileMetadata> {
+        self.with_resync(true, |s| {
+            s.tx_cmd(CMD_F_FINFO)?;
+            s.tx_str(path)?;
+            s.flush_cmd()?;
+
+            let code = StatusCode(s.rx_u8()?);
+            if !code.is_ok() {
+                return Err(Error::FileSystem { path: path.to_string(), code });
+            }
 
-        Ok(self.rx_file_metadata()?)
+            s.rx_file_metadata()
+        })
     }
 
     /// Set the current file handle, given a path on the SD card.
-    pub fn open_file(&mut self, path: &str, mode: u8) -> anyhow::Result<()> {
+    pub fn open_file(&mut self, path: &str, mode: u8) -> Result<()> {
         self.tx_cmd(CMD_F_FOPN)?;
         self.tx_u8(mode)?;
         self.tx_str(path)?;
-        self.check_status()?;
+
+        let code = self.get_status()?;
+        if !code.is_ok() {
+            return Err(Error::FileSystem { path: path.to_string(), code });
+        }
         Ok(())
     }
 }
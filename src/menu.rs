@@ -0,0 +1,223 @@
+//! The protocol spoken to the menu running on the Mega Drive, via the FIFO.
+//!
+//! Commands are a `*` followed by a single command character, and any
+//! arguments. Integers are big-endian and strings are prefixed with their
+//! length as a 16-bit integer.
+//!
+//! `MenuCommand` has typed variants for the commands megalink uses to boot
+//! games. Other commands the menu understands can be sent with
+//! `MenuCommand::Raw`.
+
+use std::convert::TryFrom;
+use byteorder::{ByteOrder, BigEndian};
+use crate::error::{Error, Result};
+
+/// The byte sent by the menu once it has started after a reset.
+pub const MENU_READY: u8 = b'r';
+
+const CMD_PREFIX: u8 = b'*';
+const CMD_TEST: u8 = b't';
+const CMD_SKIP_FPGA: u8 = b'u';
+const CMD_START_GAME: u8 = b'g';
+
+const TEST_RESPONSE: u8 = b'k';
+
+/// A command understood by the menu.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MenuCommand {
+    /// Check that the menu is listening.
+    Test,
+    /// Keep the current FPGA core when the next game is started.
+    SkipFpga,
+    /// Start the game in ROM memory. Uploaded games have a path starting
+    /// with `USB:`; other paths are expected to name a game on the SD card,
+    /// which the menu loads first.
+    StartGame {
+        /// The size of the ROM, in bytes.
+        size: u32,
+        /// The path of the game.
+        path: String,
+    },
+    /// Any other command.
+    Raw {
+        /// The command character, sent after the `*`.
+        cmd: u8,
+        /// The encoded arguments.
+        args: Vec<u8>,
+        /// The response the menu sends.
+        response: MenuResponse,
+    },
+}
+
+/// The response expected from the menu after sending a command.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MenuResponse {
+    /// The menu does not respond.
+    None,
+    /// The menu responds with exactly this byte.
+    Exact(u8),
+    /// The menu responds with a single byte, but the value is not checked.
+    Any,
+}
+
+impl MenuCommand {
+    /// Get the name of the command, used for debug printing.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MenuCommand::Test => "test",
+            MenuCommand::SkipFpga => "skip-fpga",
+            MenuCommand::StartGame { .. } => "start-game",
+            MenuCommand::Raw { .. } => "raw",
+        }
+    }
+
+    /// Get the response the menu sends for this command.
+    pub fn expected_response(&self) -> MenuResponse {
+        match self {
+            MenuCommand::Test => MenuResponse::Exact(TEST_RESPONSE),
+            MenuCommand::SkipFpga => MenuResponse::None,
+            // The menu sends a byte, but what it means is not known, so
+            // it is read and ignored.
+            MenuCommand::StartGame { .. } => MenuResponse::Any,
+            MenuCommand::Raw { response, .. } => *response,
+        }
+    }
+
+    /// Check the response received for this command.
+    pub fn check_response(&self, resp: u8) -> Result<()> {
+        match self.expected_response() {
+            MenuResponse::Exact(v) if v != resp => {
+                Err(Error::MenuResponse {
+                    command: self.name(),
+                    expected: Some(v),
+                    actual: resp,
+                })
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Encode the command into the bytes written to the FIFO.
+    ///
+    /// Fails if a string argument is too long to encode.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = vec![CMD_PREFIX];
+        match self {
+            MenuCommand::Test => out.push(CMD_TEST),
+            MenuCommand::SkipFpga => out.push(CMD_SKIP_FPGA),
+            MenuCommand::StartGame { size, path } => {
+                out.push(CMD_START_GAME);
+
+                let mut buf = [0u8; 4];
+                BigEndian::write_u32(&mut buf, *size);
+                out.extend_from_slice(&buf);
+
+                let len = u16::try_from(path.len()).map_err(|_| {
+                    Error::InvalidMenuCommand(format!("path is {} bytes, the most is {}",
+                                                      path.len(), u16::MAX))
+                })?;
+                let mut buf = [0u8; 2];
+                BigEndian::write_u16(&mut buf, len);
+                out.extend_from_slice(&buf);
+                out.extend_from_slice(path.as_bytes());
+            },
+            MenuCommand::Raw { cmd, args, .. } => {
+                out.push(*cmd);
+                out.extend_from_slice(args);
+            },
+        }
+        Ok(out)
+    }
+
+    /// Decode a command from the start of `data`.
+    ///
+    /// Returns the command and the number of bytes it occupied, or `None` if
+    /// `data` does not contain a complete command. Commands without a typed
+    /// variant can't be decoded, since their length is not known.
+    pub fn decode(data: &[u8]) -> Result<Option<(MenuCommand, usize)>> {
+        if data.len() < 2 {
+            return Ok(None);
+        }
+
+        if data[0] != CMD_PREFIX {
+            return Err(Error::InvalidMenuCommand(format!("invalid prefix {:02x}", data[0])));
+        }
+
+        let result = match data[1] {
+            CMD_TEST => Some((MenuCommand::Test, 2)),
+            CMD_SKIP_FPGA => Some((MenuCommand::SkipFpga, 2)),
+            CMD_START_GAME => {
+                if data.len() < 8 {
+                    return Ok(None);
+                }
+
+                let size = BigEndian::read_u32(&data[2..6]);
+                let len = BigEndian::read_u16(&data[6..8]) as usize;
+                if data.len() < 8 + len {
+                    return Ok(None);
+                }
+
+                let path = String::from_utf8(data[8..8 + len].to_vec())
+                    .map_err(|e| Error::InvalidMenuCommand(format!("invalid path: {}", e)))?;
+                Some((MenuCommand::StartGame { size, path }, 8 + len))
+            },
+            other => return Err(Error::InvalidMenuCommand(format!("unknown command {:02x}", other))),
+        };
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let cmds = [
+            MenuCommand::Test,
+            MenuCommand::SkipFpga,
+            MenuCommand::StartGame { size: 0x123456, path: "USB:game.md".to_string() },
+        ];
+
+        let mut data = Vec::new();
+        for cmd in &cmds {
+            data.extend(cmd.encode().unwrap());
+        }
+
+        let mut rest = &data[..];
+        for cmd in &cmds {
+            let (decoded, n) = MenuCommand::decode(rest).unwrap().unwrap();
+            assert_eq!(&decoded, cmd);
+            rest = &rest[n..];
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn start_game_encoding() {
+        let cmd = MenuCommand::StartGame { size: 0x20000, path: "USB:a.md".to_string() };
+        assert_eq!(cmd.encode().unwrap(), b"*g\x00\x02\x00\x00\x00\x08USB:a.md");
+        assert!(MenuCommand::decode(b"*g\x00\x02\x00\x00\x00\x08USB").unwrap().is_none());
+    }
+
+    #[test]
+    fn long_path() {
+        let cmd = MenuCommand::StartGame { size: 0, path: "x".repeat(0x10000) };
+        assert!(matches!(cmd.encode(), Err(Error::InvalidMenuCommand(_))));
+    }
+
+    #[test]
+    fn responses() {
+        assert!(MenuCommand::Test.check_response(b'k').is_ok());
+        assert!(MenuCommand::Test.check_response(b'x').is_err());
+
+        let start = MenuCommand::StartGame { size: 0, path: String::new() };
+        assert_eq!(start.expected_response(), MenuResponse::Any);
+        assert!(start.check_response(0).is_ok());
+        assert!(start.check_response(3).is_ok());
+
+        let raw = MenuCommand::Raw { cmd: b'x', args: vec![1, 2], response: MenuResponse::Any };
+        assert_eq!(raw.encode().unwrap(), b"*x\x01\x02");
+        assert!(raw.check_response(0x55).is_ok());
+    }
+}
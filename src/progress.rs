@@ -0,0 +1,111 @@
+//! Progress reporting for long-running transfers.
+
+use std::time::{Duration, Instant};
+
+/// The phase of an operation which is being reported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Phase {
+    /// Sending data to the device.
+    Upload,
+    /// Receiving data from the device.
+    Download,
+    /// Checking data which was sent to the device.
+    Verify,
+    /// Starting a game. This is a single step, reported once the menu has
+    /// been told to start the game.
+    Boot,
+}
+
+impl Phase {
+    /// Get the lower-case name, used for debug printing.
+    pub fn lower_name(self) -> &'static str {
+        match self {
+            Phase::Upload => "upload",
+            Phase::Download => "download",
+            Phase::Verify => "verify",
+            Phase::Boot => "boot",
+        }
+    }
+}
+
+/// A progress update.
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressEvent {
+    /// The phase of the operation.
+    pub phase: Phase,
+    /// The number of bytes transferred so far, or of steps done for phases
+    /// which are not transfers.
+    pub done: u64,
+    /// The total number of bytes to transfer, or of steps.
+    pub total: u64,
+    /// The time since the phase started.
+    pub elapsed: Duration,
+}
+
+impl ProgressEvent {
+    /// Get the average throughput so far, in bytes per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.done as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Get the fraction of the transfer which is complete, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        if self.total > 0 {
+            (self.done as f64 / self.total as f64).min(1.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Implement this trait to receive progress updates from bulk operations.
+pub trait Progress {
+    /// Called each time more data has been transferred.
+    fn update(&mut self, event: &ProgressEvent);
+
+    /// Called once a phase has finished.
+    fn finish(&mut self, _event: &ProgressEvent) {}
+}
+
+impl<F: FnMut(&ProgressEvent)> Progress for F {
+    fn update(&mut self, event: &ProgressEvent) {
+        self(event)
+    }
+}
+
+/// The state of the operation currently being reported.
+pub(crate) struct Tracker {
+    phase: Phase,
+    done: u64,
+    total: u64,
+    start: Instant,
+}
+
+impl Tracker {
+    pub fn new(phase: Phase, total: u64) -> Tracker {
+        Tracker {
+            phase,
+            done: 0,
+            total,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, n: u64) {
+        self.done += n;
+    }
+
+    pub fn event(&self) -> ProgressEvent {
+        ProgressEvent {
+            phase: self.phase,
+            done: self.done,
+            total: self.total,
+            elapsed: self.start.elapsed(),
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Pseudo-terminals, for standing in for the device's serial port.
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::Duration;
+use crate::{Result, Transport};
+
+/// The master side of a pseudo-terminal. Programs open the slave side,
+/// given by `path`, as if it were a serial port.
+pub struct Pty {
+    master: File,
+    // Holding the slave open stops reads from failing while no program has
+    // it open.
+    _slave: File,
+    path: PathBuf,
+    timeout: Duration,
+}
+
+impl Pty {
+    /// Create a pseudo-terminal in raw mode.
+    pub fn open() -> Result<Pty> {
+        let mut master = -1;
+        let mut slave = -1;
+        let ret = unsafe {
+            libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), ptr::null())
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let master = unsafe { File::from_raw_fd(master) };
+        let slave = unsafe { File::from_raw_fd(slave) };
+
+        let mut name = [0 as libc::c_char; 256];
+        let ret = unsafe { libc::ttyname_r(slave.as_raw_fd(), name.as_mut_ptr(), name.len()) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret).into());
+        }
+        let path = unsafe { CStr::from_ptr(name.as_ptr()) };
+        let path = PathBuf::from(path.to_string_lossy().into_owned());
+
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        Ok(Pty { master, _slave: slave, path, timeout: Duration::from_secs(1) })
+    }
+
+    /// Get the path of the slave side.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Read for Pty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut fd = libc::pollfd { fd: self.master.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let timeout = self.timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        match unsafe { libc::poll(&mut fd, 1, timeout) } {
+            0 => Err(io::Error::new(io::ErrorKind::TimedOut, "pty read timed out")),
+            n if n < 0 => Err(io::Error::last_os_error()),
+            _ => self.master.read(buf),
+        }
+    }
+}
+
+impl Write for Pty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.master.flush()
+    }
+}
+
+impl Transport for Pty {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut pty = Pty::open().unwrap();
+        let mut slave = OpenOptions::new().read(true).write(true).open(pty.path()).unwrap();
+
+        // Raw mode passes every byte through unchanged, including ones a
+        // terminal would normally interpret.
+        let data: Vec<u8> = (0..=255).collect();
+        slave.write_all(&data).unwrap();
+        let mut buf = vec![0; data.len()];
+        pty.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        pty.write_all(&data).unwrap();
+        let mut buf = vec![0; data.len()];
+        slave.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        pty.set_timeout(Duration::from_millis(10)).unwrap();
+        let e = pty.read(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
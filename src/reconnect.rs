@@ -0,0 +1,37 @@
+//! Reconnecting after the device re-enumerates on USB.
+
+use std::time::Duration;
+
+/// How to reconnect after the device drops off USB and comes back, such as
+/// when changing mode.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// The total time to wait for the device to come back.
+    pub timeout: Duration,
+    /// The time to wait for the old device to disappear. If it does not,
+    /// reconnecting carries on anyway.
+    pub disconnect_timeout: Duration,
+    /// The delay before the first attempt to reopen the port.
+    pub initial_delay: Duration,
+    /// The longest delay between attempts. Delays double after each attempt
+    /// until they reach this.
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            timeout: Duration::from_secs(10),
+            disconnect_timeout: Duration::from_secs(2),
+            initial_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Get the delay to use after `delay`.
+    pub(crate) fn next_delay(&self, delay: Duration) -> Duration {
+        (delay * 2).min(self.max_delay)
+    }
+}
@@ -0,0 +1,211 @@
+//! Detection of the system a ROM image is for, and parsing of ROM headers.
+
+use byteorder::{ByteOrder, BigEndian};
+
+/// Offsets at which a Master System / Game Gear header can be found.
+const SMS_HEADER_OFFSETS: [usize; 3] = [0x7ff0, 0x3ff0, 0x1ff0];
+const SMS_HEADER_MAGIC: &[u8] = b"TMR SEGA";
+
+const MD_HEADER_OFFSET: usize = 0x100;
+const MD_HEADER_MAGIC: &[u8] = b"SEGA";
+const MD_HEADER_SIZE: usize = 0x100;
+
+const MARS_HEADER_MAGIC: &[u8] = b"SEGA 32X";
+const MARS_SECURITY_OFFSET: usize = 0x3c0;
+const MARS_SECURITY_MAGIC: &[u8] = b"MARS CHECK MODE";
+
+/// A system which the Mega Everdrive Pro can run games for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum System {
+    /// The Mega Drive / Genesis.
+    MegaDrive,
+    /// The 32X add-on for the Mega Drive.
+    Mars,
+    /// The Master System.
+    MasterSystem,
+    /// The Game Gear.
+    GameGear,
+}
+
+impl System {
+    /// Get the lower-case name, used for debug printing.
+    pub fn lower_name(self) -> &'static str {
+        match self {
+            System::MegaDrive => "mega drive",
+            System::Mars => "32x",
+            System::MasterSystem => "master system",
+            System::GameGear => "game gear",
+        }
+    }
+
+    /// Get the file extension used for ROMs for this system.
+    pub fn extension(self) -> &'static str {
+        match self {
+            System::MegaDrive => "md",
+            System::Mars => "32x",
+            System::MasterSystem => "sms",
+            System::GameGear => "gg",
+        }
+    }
+
+    /// Returns true if this system needs a different FPGA core to the
+    /// default Mega Drive one.
+    pub fn needs_core(self) -> bool {
+        match self {
+            System::MegaDrive | System::Mars => false,
+            System::MasterSystem | System::GameGear => true,
+        }
+    }
+
+    /// Guess the system from a file name's extension.
+    pub fn from_file_name(name: &str) -> Option<System> {
+        let ext = name.rsplit('.').next()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "md" | "gen" | "smd" => Some(System::MegaDrive),
+            "32x" => Some(System::Mars),
+            "sms" => Some(System::MasterSystem),
+            "gg" => Some(System::GameGear),
+            _ => None,
+        }
+    }
+
+    /// Guess the system from the ROM header.
+    pub fn from_header(data: &[u8]) -> Option<System> {
+        if has_magic(data, MD_HEADER_OFFSET, MARS_HEADER_MAGIC)
+            || has_magic(data, MARS_SECURITY_OFFSET, MARS_SECURITY_MAGIC) {
+            return Some(System::Mars);
+        }
+
+        if has_magic(data, MD_HEADER_OFFSET, MD_HEADER_MAGIC) {
+            return Some(System::MegaDrive);
+        }
+
+        for &offset in SMS_HEADER_OFFSETS.iter() {
+            if !has_magic(data, offset, SMS_HEADER_MAGIC) {
+                continue;
+            }
+
+            // The upper nibble of the last header byte is the region code,
+            // which also distinguishes the Game Gear.
+            return match data.get(offset + 0xf).map(|b| b >> 4) {
+                Some(5..=7) => Some(System::GameGear),
+                _ => Some(System::MasterSystem),
+            };
+        }
+
+        None
+    }
+
+    /// Detect the system a ROM is for.
+    ///
+    /// The file extension is preferred, since Master System headers are
+    /// optional and Game Gear games often claim to be Master System games.
+    /// If neither identify the system, the Mega Drive is assumed.
+    pub fn detect(name: &str, data: &[u8]) -> System {
+        System::from_file_name(name)
+            .or_else(|| System::from_header(data))
+            .unwrap_or(System::MegaDrive)
+    }
+}
+
+/// The external RAM declared in a Mega Drive ROM header.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExternalRam {
+    /// The RAM type byte.
+    pub kind: u8,
+    /// The start address of the RAM, in the 68000's address space.
+    pub start: u32,
+    /// The end address of the RAM, in the 68000's address space.
+    pub end: u32,
+}
+
+/// The header of a Mega Drive or 32X ROM.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+    pub console: String,
+    pub copyright: String,
+    pub domestic_title: String,
+    pub overseas_title: String,
+    pub serial: String,
+    pub checksum: u16,
+    pub devices: String,
+    pub rom_start: u32,
+    pub rom_end: u32,
+    pub ram_start: u32,
+    pub ram_end: u32,
+    pub external_ram: Option<ExternalRam>,
+    pub regions: String,
+}
+
+impl Header {
+    /// Parse the header from a ROM image.
+    ///
+    /// Returns `None` if the image is too small to contain a header, or does
+    /// not have the `SEGA` signature.
+    pub fn parse(data: &[u8]) -> Option<Header> {
+        let h = data.get(MD_HEADER_OFFSET..MD_HEADER_OFFSET + MD_HEADER_SIZE)?;
+        if !h.starts_with(MD_HEADER_MAGIC) {
+            return None;
+        }
+
+        let text = |start: usize, len: usize| -> String {
+            h[start..start + len].iter()
+                .map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { ' ' })
+                .collect::<String>()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let u32_at = |start: usize| BigEndian::read_u32(&h[start..start + 4]);
+
+        let external_ram = if &h[0xb0..0xb2] == b"RA" {
+            Some(ExternalRam {
+                kind: h[0xb2],
+                start: u32_at(0xb4),
+                end: u32_at(0xb8),
+            })
+        } else {
+            None
+        };
+
+        Some(Header {
+            console: text(0x00, 16),
+            copyright: text(0x10, 16),
+            domestic_title: text(0x20, 48),
+            overseas_title: text(0x50, 48),
+            serial: text(0x80, 14),
+            checksum: BigEndian::read_u16(&h[0x8e..0x90]),
+            devices: text(0x90, 16),
+            rom_start: u32_at(0xa0),
+            rom_end: u32_at(0xa4),
+            ram_start: u32_at(0xa8),
+            ram_end: u32_at(0xac),
+            external_ram,
+            regions: text(0xf0, 3),
+        })
+    }
+
+    /// Returns true if the header identifies the ROM as a 32X game.
+    pub fn is_mars(&self) -> bool {
+        self.console.as_bytes().starts_with(MARS_HEADER_MAGIC)
+    }
+}
+
+/// Calculate the checksum of a Mega Drive ROM, as stored in the header.
+///
+/// This is the sum of all big-endian words after the header.
+pub fn checksum(data: &[u8]) -> u16 {
+    let body = data.get(MD_HEADER_OFFSET + MD_HEADER_SIZE..).unwrap_or(&[]);
+    body.chunks(2).fold(0u16, |sum, w| {
+        let v = match w {
+            [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+            [hi] => u16::from_be_bytes([*hi, 0]),
+            _ => 0,
+        };
+        sum.wrapping_add(v)
+    })
+}
+
+fn has_magic(data: &[u8], offset: usize, magic: &[u8]) -> bool {
+    data.get(offset..offset + magic.len()) == Some(magic)
+}
@@ -0,0 +1,382 @@
+//! An offline database of known ROM dumps, used to name games and warn
+//! about bad dumps.
+//!
+//! Entries come from two places:
+//!
+//! - No-Intro style DAT files, which name games and mark bad dumps.
+//! - Tables of names, with one entry per line of the form `crc32 name`, for
+//!   example `0123abcd Some Game (Europe)`. Blank lines and lines starting
+//!   with `#` are ignored.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use crate::error::{Error, Result};
+use crate::hash::{self, Crc32, HashReader, Sha1};
+
+/// A known ROM dump.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RomEntry {
+    /// The name of the game.
+    pub name: String,
+    /// The CRC-32 of the dump.
+    pub crc32: u32,
+    /// The SHA-1 of the dump, as lower-case hex, if known.
+    pub sha1: Option<String>,
+    /// The size of the dump, if known.
+    pub size: Option<u64>,
+    /// True if the dump is known to be bad.
+    pub bad_dump: bool,
+}
+
+/// A database of ROM dumps, keyed by CRC-32.
+#[derive(Clone, Debug, Default)]
+pub struct RomDb {
+    entries: HashMap<u32, Vec<RomEntry>>,
+}
+
+impl RomDb {
+    /// Create an empty database.
+    pub fn new() -> RomDb {
+        RomDb::default()
+    }
+
+    /// The number of entries in the database.
+    pub fn len(&self) -> usize {
+        self.entries.values().map(|v| v.len()).sum()
+    }
+
+    /// Returns true if the database has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Add an entry, merging it with any existing entry for the same dump.
+    pub fn insert(&mut self, entry: RomEntry) {
+        let entries = self.entries.entry(entry.crc32).or_default();
+
+        let existing = entries.iter_mut().find(|e| {
+            match (e.sha1.as_ref(), entry.sha1.as_ref()) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+        });
+
+        match existing {
+            Some(e) => {
+                if e.name.is_empty() {
+                    e.name = entry.name;
+                }
+                e.sha1 = e.sha1.take().or(entry.sha1);
+                e.size = e.size.or(entry.size);
+                e.bad_dump |= entry.bad_dump;
+            },
+            None => entries.push(entry),
+        }
+    }
+
+    /// Load a table of names, in the format described in the module
+    /// documentation.
+    pub fn load_table(&mut self, text: &str) -> Result<()> {
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let err = |message: String| Error::Database { line: Some(n + 1), message };
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let mut next = || parts.next()
+                .ok_or_else(|| err("missing fields".to_string()));
+
+            let crc32 = u32::from_str_radix(next()?, 16)
+                .map_err(|e| err(format!("invalid crc: {}", e)))?;
+            let name = next()?.trim().to_string();
+
+            self.insert(RomEntry {
+                name,
+                crc32,
+                ..RomEntry::default()
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Load a No-Intro style (Logiqx XML) DAT file.
+    pub fn load_dat(&mut self, text: &str) -> Result<()> {
+        let mut game_name = String::new();
+
+        for tag in Tags::new(text) {
+            match tag.name {
+                "game" | "machine" => {
+                    game_name = tag.attr("name").unwrap_or_default();
+                },
+                "rom" => {
+                    let crc = match tag.attr("crc") {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    let crc32 = u32::from_str_radix(&crc, 16)
+                        .map_err(|e| Error::Database {
+                            line: None,
+                            message: format!("invalid crc {} for {}: {}", crc, game_name, e),
+                        })?;
+
+                    self.insert(RomEntry {
+                        name: game_name.clone(),
+                        crc32,
+                        sha1: tag.attr("sha1").map(|s| s.to_ascii_lowercase()),
+                        size: tag.attr("size").and_then(|s| s.parse().ok()),
+                        bad_dump: tag.attr("status").as_deref() == Some("baddump"),
+                    });
+                },
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the entry for a ROM image.
+    pub fn find(&self, data: &[u8]) -> Option<&RomEntry> {
+        self.find_by_hash(Crc32::digest(data), || Sha1::digest(data))
+    }
+
+    fn find_by_hash(&self, crc32: u32, sha1: impl FnOnce() -> [u8; 20]) -> Option<&RomEntry> {
+        let candidates = self.entries.get(&crc32)?;
+
+        if candidates.len() > 1 || candidates.iter().any(|e| e.sha1.is_some()) {
+            let sha1 = hash::to_hex(&sha1());
+            if let Some(e) = candidates.iter().find(|e| e.sha1.as_deref() == Some(&sha1)) {
+                return Some(e);
+            }
+        }
+
+        candidates.iter().find(|e| e.sha1.is_none())
+    }
+
+    /// Find the entry for a ROM image, reading it from a stream.
+    pub fn find_reader(&self, data: impl Read) -> io::Result<Option<&RomEntry>> {
+        let mut reader = HashReader::new(data);
+        io::copy(&mut reader, &mut io::sink())?;
+        let (sha1, crc32) = reader.finish();
+        Ok(self.find_by_hash(crc32, || sha1))
+    }
+}
+
+/// A single XML tag, with its attributes.
+struct Tag<'a> {
+    name: &'a str,
+    attrs: &'a str,
+}
+
+impl<'a> Tag<'a> {
+    fn attr(&self, key: &str) -> Option<String> {
+        let mut rest = self.attrs;
+        loop {
+            let eq = rest.find('=')?;
+            let name = rest[..eq].trim();
+            rest = rest[eq + 1..].trim_start();
+
+            let quote = rest.chars().next()?;
+            if quote != '"' && quote != '\'' {
+                return None;
+            }
+            let end = rest[1..].find(quote)? + 1;
+            let value = &rest[1..end];
+            rest = &rest[end + 1..];
+
+            if name == key {
+                return Some(unescape(value));
+            }
+        }
+    }
+}
+
+/// An iterator over the opening tags in an XML document.
+struct Tags<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tags<'a> {
+    fn new(text: &'a str) -> Tags<'a> {
+        Tags { rest: text }
+    }
+}
+
+impl<'a> Tags<'a> {
+    /// Skip past `end`, or to the end of the document if it is missing.
+    fn skip_past(&mut self, end: &str) {
+        self.rest = match self.rest.find(end) {
+            Some(i) => &self.rest[i + end.len()..],
+            None => "",
+        };
+    }
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Tag<'a>;
+
+    fn next(&mut self) -> Option<Tag<'a>> {
+        loop {
+            let start = self.rest.find('<')?;
+            self.rest = &self.rest[start..];
+
+            // Comments and CDATA sections may contain anything, including
+            // text which looks like tags.
+            if self.rest.starts_with("<!--") {
+                self.skip_past("-->");
+                continue;
+            }
+            if self.rest.starts_with("<![CDATA[") {
+                self.skip_past("]]>");
+                continue;
+            }
+
+            // Find the end of the tag, ignoring any `>` in quoted values.
+            let mut quote = None;
+            let end = self.rest.char_indices().skip(1).find(|&(_, c)| {
+                match quote {
+                    Some(q) => {
+                        if c == q {
+                            quote = None;
+                        }
+                        false
+                    },
+                    None if c == '"' || c == '\'' => {
+                        quote = Some(c);
+                        false
+                    },
+                    None => c == '>',
+                }
+            })?.0;
+            let body = &self.rest[1..end];
+            self.rest = &self.rest[end + 1..];
+
+            if body.starts_with('/') || body.starts_with('?') || body.starts_with('!') {
+                continue;
+            }
+
+            let body = body.trim_end_matches('/');
+            let split = body.find(char::is_whitespace).unwrap_or(body.len());
+            return Some(Tag {
+                name: &body[..split],
+                attrs: &body[split..],
+            });
+        }
+    }
+}
+
+/// Replace the XML entities in an attribute value.
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest.find(';').and_then(|end| {
+            let c = match &rest[1..end] {
+                "quot" => '"',
+                "apos" => '\'',
+                "lt" => '<',
+                "gt" => '>',
+                "amp" => '&',
+                name => {
+                    let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => name.strip_prefix('#')?.parse().ok()?,
+                    };
+                    std::char::from_u32(code)?
+                },
+            };
+            Some((c, end))
+        });
+
+        match entity {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAT: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE datafile PUBLIC "-//Logiqx//DTD ROM Management Datafile//EN" "http://www.logiqx.com/Dats/datafile.dtd">
+<datafile>
+	<header>
+		<name>Sega - Mega Drive - Genesis</name>
+	</header>
+	<!-- a > b, and <rom name="commented" size="3" crc="00000000"/> is not a rom -->
+	<game name="Tom &amp; Jerry &#x28;USA&#41; &quot;Beta&quot;">
+		<description>Tom &amp; Jerry</description>
+		<rom name="a > b.md" size="3" crc="352441C2" sha1="A9993E364706816ABA3E25717850C26C9CD0D89D"/>
+	</game>
+	<game name='Bad &lt;Dump&gt;'>
+		<rom name="bad.md" size="9" crc="cbf43926" status="baddump"/>
+	</game>
+</datafile>
+"#;
+
+    #[test]
+    fn dat_parsing() {
+        let mut db = RomDb::new();
+        db.load_dat(DAT).unwrap();
+        assert_eq!(db.len(), 2);
+        assert!(!db.entries.contains_key(&0));
+
+        let entry = db.find(b"abc").unwrap();
+        assert_eq!(entry.name, "Tom & Jerry (USA) \"Beta\"");
+        assert_eq!(entry.crc32, 0x352441c2);
+        assert_eq!(entry.sha1.as_deref(), Some("a9993e364706816aba3e25717850c26c9cd0d89d"));
+        assert_eq!(entry.size, Some(3));
+        assert!(!entry.bad_dump);
+
+        let entry = db.find(b"123456789").unwrap();
+        assert_eq!(entry.name, "Bad <Dump>");
+        assert!(entry.bad_dump);
+    }
+
+    #[test]
+    fn sha1_mismatch() {
+        let mut db = RomDb::new();
+        db.insert(RomEntry {
+            name: "Other".to_string(),
+            crc32: Crc32::digest(b"abc"),
+            sha1: Some("0000000000000000000000000000000000000000".to_string()),
+            ..RomEntry::default()
+        });
+        assert!(db.find(b"abc").is_none());
+    }
+
+    #[test]
+    fn unescaping() {
+        assert_eq!(unescape("a &amp;lt; b"), "a &lt; b");
+        assert_eq!(unescape("&#65;&#x42;&#X43;"), "ABC");
+        assert_eq!(unescape("& &unknown; &#xzz;"), "& &unknown; &#xzz;");
+    }
+
+    #[test]
+    fn table() {
+        let mut db = RomDb::new();
+        db.load_table("# comment\n\n352441c2 Some Game (Europe)\n").unwrap();
+        assert_eq!(db.find(b"abc").unwrap().name, "Some Game (Europe)");
+        assert_eq!(db.find_reader(&b"abc"[..]).unwrap().unwrap().name, "Some Game (Europe)");
+
+        let e = RomDb::new().load_table("nothex Game\n").unwrap_err();
+        assert!(matches!(e, Error::Database { line: Some(1), .. }));
+        let e = RomDb::new().load_table("352441c2\n").unwrap_err();
+        assert!(matches!(e, Error::Database { line: Some(1), .. }));
+    }
+}
@@ -0,0 +1,166 @@
+//! Host-side storage for cartridge saves, keyed by the hash of the ROM that
+//! created them.
+//!
+//! The layout of the save directory is:
+//!
+//! ```text
+//! <root>/current            hash of the ROM last uploaded over USB
+//! <root>/<hash>/name        file name the ROM was uploaded as
+//! <root>/<hash>/sram.bin    most recent save for the ROM
+//! <root>/<hash>/sram-*.bak  older saves which were replaced
+//! ```
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, debug};
+
+const CURRENT_FILE: &str = "current";
+const NAME_FILE: &str = "name";
+const SRAM_FILE: &str = "sram.bin";
+
+/// The default amount of SRAM to synchronise.
+pub const DEFAULT_SRAM_SIZE: usize = 0x10000;
+
+/// Configuration for synchronising SRAM between the cartridge and the host.
+///
+/// When enabled, `load_game` saves the SRAM of the previously uploaded game
+/// before replacing it, and restores the SRAM of the new game afterwards.
+/// SRAM is cleared for games which have no stored save.
+#[derive(Clone, Debug)]
+pub struct SaveSync {
+    /// The directory saves are kept in.
+    pub root: PathBuf,
+    /// The number of bytes of SRAM to synchronise.
+    pub sram_size: usize,
+    /// Whether to restore saves after uploading a game. Without this, the
+    /// SRAM is left as it was, and the new game's save is not stored.
+    pub restore: bool,
+}
+
+impl SaveSync {
+    /// Create a new save configuration, storing saves under `root`.
+    pub fn new(root: impl Into<PathBuf>) -> SaveSync {
+        SaveSync {
+            root: root.into(),
+            sram_size: DEFAULT_SRAM_SIZE,
+            restore: true,
+        }
+    }
+
+    fn game_dir(&self, hash: &str) -> PathBuf {
+        self.root.join(hash)
+    }
+
+    /// Get the hash of the game which was last uploaded, if any.
+    pub fn current(&self) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.root.join(CURRENT_FILE)) {
+            Ok(s) => {
+                let s = s.trim();
+                if s.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some(s.to_string()))
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Record the game which is now loaded on the cartridge.
+    pub fn set_current(&self, hash: &str, name: &str) -> io::Result<()> {
+        let dir = self.game_dir(hash);
+        fs::create_dir_all(&dir)?;
+        write_atomic(&dir.join(NAME_FILE), name.as_bytes())?;
+        write_atomic(&self.root.join(CURRENT_FILE), hash.as_bytes())?;
+        Ok(())
+    }
+
+    /// Record that the game on the cartridge was not uploaded over USB, so
+    /// its saves are not managed by the host.
+    pub fn clear_current(&self) -> io::Result<()> {
+        match fs::remove_file(self.root.join(CURRENT_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Load the stored save for a game.
+    pub fn load(&self, hash: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.game_dir(hash).join(SRAM_FILE)) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Store a save for a game.
+    ///
+    /// Blank SRAM is assumed to mean the game has no save, and is not stored.
+    /// If a different save is already stored, it is kept as a backup rather
+    /// than being overwritten.
+    pub fn store(&self, hash: &str, sram: &[u8]) -> io::Result<()> {
+        if is_blank(sram) {
+            debug!("SRAM for {} is blank, not storing", hash);
+            return Ok(());
+        }
+
+        let dir = self.game_dir(hash);
+        fs::create_dir_all(&dir)?;
+
+        let path = dir.join(SRAM_FILE);
+        match fs::read(&path) {
+            Ok(existing) if existing == sram => {
+                debug!("save for {} unchanged", hash);
+                return Ok(());
+            },
+            Ok(_) => {
+                let backup = backup_path(&dir)?;
+                info!("keeping previous save as {}", backup.display());
+                fs::rename(&path, &backup)?;
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+
+        info!("storing save for {}", hash);
+        write_atomic(&path, sram)
+    }
+}
+
+fn is_blank(data: &[u8]) -> bool {
+    match data.first() {
+        Some(&first) => data.iter().all(|&b| b == first),
+        None => true,
+    }
+}
+
+fn backup_path(dir: &Path) -> io::Result<PathBuf> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    for n in 0.. {
+        let name = if n == 0 {
+            format!("sram-{}.bak", now)
+        } else {
+            format!("sram-{}-{}.bak", now, n)
+        };
+
+        let path = dir.join(name);
+        if !path.exists() {
+            return Ok(path);
+        }
+    }
+
+    unreachable!()
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
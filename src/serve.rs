@@ -0,0 +1,318 @@
+//! Sharing a device over TCP.
+//!
+//! A `Server` forwards the bytes of one client at a time to the device. Each
+//! connection starts with a handshake, and then carries the serial protocol
+//! unchanged:
+//!
+//! ```text
+//! client: "MEGALINK" token (8 bytes, big-endian)
+//! server: reply (1 byte)
+//! ```
+//!
+//! The token identifies the client across reconnects. The device drops off
+//! USB when it changes mode, and the server closes the connection when it
+//! does, so the client has to reconnect. The device is then held for the
+//! client with the same token for a while, so that another client can not
+//! take it in the meantime. It is not held if the client closes the
+//! connection itself.
+
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, info, log, warn, Level};
+use crate::decode::{Decoder, Frame};
+use crate::{CancelToken, Error, Result, SerialFactory, TcpTransport, Transport};
+
+/// The start of each connection from a client.
+const HANDSHAKE_MAGIC: &[u8; 8] = b"MEGALINK";
+
+/// The server's reply when the client may use the device.
+const REPLY_OK: u8 = 0;
+/// The server's reply when another client is using the device.
+const REPLY_BUSY: u8 = 1;
+/// The server's reply when the device could not be opened.
+const REPLY_NO_DEVICE: u8 = 2;
+
+/// How long the server waits for a client's handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a new client waits for the previous one to finish.
+const BUSY_WAIT: Duration = Duration::from_millis(500);
+
+/// How long to wait for data from one side of a forwarded connection before
+/// checking the other.
+const FORWARD_POLL: Duration = Duration::from_millis(5);
+
+/// The default time the device is held for a client after it goes away.
+pub const DEFAULT_HOLD: Duration = Duration::from_secs(10);
+
+/// Lock a mutex, carrying on if another thread panicked while holding it.
+/// Nothing guarded here is left inconsistent by a panic.
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Why forwarding stopped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ForwardEnd {
+    /// The cancel token was cancelled.
+    Cancelled,
+    /// The host closed its connection.
+    HostClosed,
+    /// The device went away, such as when it changes mode.
+    DeviceGone,
+}
+
+/// Forward traffic between the host and the device until one of them goes
+/// away, logging the decoded frames at `level`.
+pub fn forward(host: &mut dyn Transport, device: &mut dyn Transport, decoder: &mut Decoder, level: Level, cancel: &CancelToken) -> Result<ForwardEnd> {
+    host.set_timeout(FORWARD_POLL)?;
+    device.set_timeout(FORWARD_POLL)?;
+
+    let mut buf = [0u8; 4096];
+    while !cancel.is_cancelled() {
+        match host.read(&mut buf) {
+            Ok(0) => return Ok(ForwardEnd::HostClosed),
+            Ok(n) => {
+                // The host's commands are only seen here as bytes, so mark
+                // them in any capture as the decoder finds them.
+                let frames = decoder.push_tx(&buf[..n]);
+                for frame in &frames {
+                    if let Frame::Command(cmd) = frame {
+                        device.mark_command(cmd.code())?;
+                    }
+                }
+                log_frames(level, frames);
+                if device.write_all(&buf[..n]).is_err() {
+                    return Ok(ForwardEnd::DeviceGone);
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::TimedOut => {},
+            Err(_) => return Ok(ForwardEnd::HostClosed),
+        }
+
+        match device.read(&mut buf) {
+            Ok(0) => return Ok(ForwardEnd::DeviceGone),
+            Ok(n) => {
+                log_frames(level, decoder.push_rx(&buf[..n]));
+                if host.write_all(&buf[..n]).is_err() {
+                    return Ok(ForwardEnd::HostClosed);
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::TimedOut => {},
+            Err(_) => return Ok(ForwardEnd::DeviceGone),
+        }
+    }
+    Ok(ForwardEnd::Cancelled)
+}
+
+/// Log decoded frames, marking which side sent each one.
+pub fn log_frames(level: Level, frames: Vec<Frame>) {
+    for frame in frames {
+        log!(level, "{} {}", frame.direction(), frame);
+    }
+}
+
+/// Which client is using the device.
+struct Owner {
+    busy: bool,
+    token: Option<u64>,
+    released: Instant,
+}
+
+/// Shares a device over TCP, with one client at a time.
+pub struct Server<F> {
+    factory: Arc<Mutex<F>>,
+    owner: Arc<(Mutex<Owner>, Condvar)>,
+    hold: Duration,
+}
+
+impl<F: SerialFactory + Send + 'static> Server<F> {
+    /// Create a server for the device opened by `factory`.
+    pub fn new(factory: F) -> Server<F> {
+        Server {
+            factory: Arc::new(Mutex::new(factory)),
+            owner: Arc::new((
+                Mutex::new(Owner { busy: false, token: None, released: Instant::now() }),
+                Condvar::new(),
+            )),
+            hold: DEFAULT_HOLD,
+        }
+    }
+
+    /// Set how long the device is held for a client after it goes away,
+    /// so the client can reconnect after changing mode.
+    pub fn set_hold(&mut self, hold: Duration) {
+        self.hold = hold;
+    }
+
+    /// Accept connections from `listener`, serving each on its own thread.
+    /// This never returns.
+    pub fn run(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("accepting connection failed: {}", e);
+                    continue;
+                },
+            };
+            // The client may already have gone.
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(e) => {
+                    warn!("accepted connection failed: {}", e);
+                    continue;
+                },
+            };
+
+            let factory = self.factory.clone();
+            let owner = self.owner.clone();
+            let hold = self.hold;
+            thread::spawn(move || {
+                if let Err(e) = serve_client(stream, peer, &factory, &owner, hold) {
+                    warn!("{}: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+/// Releases the device when dropped, even if serving the client panicked.
+struct Claim<'a> {
+    owner: &'a (Mutex<Owner>, Condvar),
+    /// Whether to hold the device for the client after releasing it.
+    hold: bool,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        let (state, released) = self.owner;
+        let mut state = lock(state);
+        state.busy = false;
+        if !self.hold {
+            state.token = None;
+        }
+        state.released = Instant::now();
+        released.notify_all();
+    }
+}
+
+/// Wait for the device to be free for the client with `token`, and claim it.
+fn claim(owner: &(Mutex<Owner>, Condvar), token: u64, hold: Duration) -> Option<Claim<'_>> {
+    let (state, released) = owner;
+    // The previous client may have only just closed its connection.
+    let mut state = released.wait_timeout_while(lock(state), BUSY_WAIT, |s| s.busy)
+        .unwrap_or_else(PoisonError::into_inner).0;
+    let held = state.token.is_some_and(|t| t != token) && state.released.elapsed() < hold;
+    if state.busy || held {
+        return None;
+    }
+    state.busy = true;
+    state.token = Some(token);
+    Some(Claim { owner, hold: true })
+}
+
+fn serve_client<F: SerialFactory>(stream: TcpStream, peer: SocketAddr, factory: &Mutex<F>, owner: &(Mutex<Owner>, Condvar), hold: Duration) -> Result<()> {
+    let mut client = TcpTransport::new(stream)?;
+    client.set_timeout(HANDSHAKE_TIMEOUT)?;
+    let mut hello = [0u8; 16];
+    client.read_exact(&mut hello)?;
+    if &hello[..8] != HANDSHAKE_MAGIC {
+        return Err(Error::InvalidData("not a megalink client".to_string()));
+    }
+    let token = u64::from_be_bytes(hello[8..].try_into().expect("8 bytes"));
+
+    let mut claim = match claim(owner, token, hold) {
+        Some(claim) => claim,
+        None => {
+            info!("refusing {}: device in use", peer);
+            client.write_all(&[REPLY_BUSY])?;
+            return Ok(());
+        },
+    };
+
+    // Closing the connection tells the client that the device has gone, for
+    // example while it changes mode.
+    let opened = lock(factory).open();
+    let mut device = match opened {
+        Ok(device) => device,
+        Err(e) => {
+            debug!("refusing {}: unable to open device: {}", peer, e);
+            client.write_all(&[REPLY_NO_DEVICE])?;
+            return Ok(());
+        },
+    };
+    client.write_all(&[REPLY_OK])?;
+    info!("{} connected", peer);
+
+    let mut decoder = Decoder::new();
+    let end = forward(&mut client, &mut device, &mut decoder, Level::Debug, &CancelToken::new())?;
+    log_frames(Level::Debug, decoder.finish());
+    match end {
+        ForwardEnd::DeviceGone => info!("{} disconnected: device went away", peer),
+        _ => {
+            info!("{} disconnected", peer);
+            claim.hold = false;
+        },
+    }
+    Ok(())
+}
+
+/// Make a token which is unlikely to be used by any other client.
+fn random_token() -> u64 {
+    // `RandomState` is seeded randomly, which is enough to tell clients
+    // apart without another dependency.
+    let mut h = RandomState::new().build_hasher();
+    h.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+    h.write_u32(std::process::id());
+    h.finish()
+}
+
+/// A factory which connects to a device shared with a `Server`, such as
+/// with `megalink serve`.
+///
+/// Each factory has its own token, so the server holds the device for it
+/// while it reconnects after a mode change.
+pub struct TcpFactory {
+    addr: String,
+    token: u64,
+}
+
+impl TcpFactory {
+    /// Connect to `addr`, which is in the form `host:port`.
+    pub fn new(addr: impl Into<String>) -> TcpFactory {
+        TcpFactory { addr: addr.into(), token: random_token() }
+    }
+}
+
+impl SerialFactory for TcpFactory {
+    type Transport = TcpTransport;
+
+    fn open(&mut self) -> Result<TcpTransport> {
+        let mut t = TcpTransport::connect(&self.addr)?;
+        let mut hello = HANDSHAKE_MAGIC.to_vec();
+        hello.extend_from_slice(&self.token.to_be_bytes());
+        t.write_all(&hello)?;
+
+        let mut reply = [0u8; 1];
+        match t.read_exact(&mut reply) {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(Error::other(format!("{} closed the connection", self.addr)));
+            },
+            Err(e) => return Err(e.into()),
+        }
+        match reply[0] {
+            REPLY_OK => Ok(t),
+            REPLY_BUSY => Err(Error::other(format!("device at {} is in use", self.addr))),
+            REPLY_NO_DEVICE => Err(Error::other(format!("device at {} is not connected", self.addr))),
+            other => Err(Error::InvalidData(format!("unexpected handshake reply {:02x}", other))),
+        }
+    }
+}
@@ -0,0 +1,732 @@
+//! A software simulation of the Mega Everdrive Pro.
+//!
+//! The simulator speaks the same command protocol as the cartridge, over any
+//! `Transport`. It models the memory regions, flash (optionally backed by a
+//! file), an SD card backed by a host directory, mode changes which make the
+//! device drop off USB and come back, and enough of the menu to boot games.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use byteorder::{ByteOrder, BigEndian};
+use log::{debug, warn};
+use crate::menu::MENU_READY;
+use crate::{pipe, Error, FpgaSource, MenuCommand, MenuResponse, Mode, PipeTransport, Region, ResetMode};
+use crate::{Result, SerialFactory, StatusCode, Transport};
+use crate::{ACK_BLOCK_SIZE, MODE_SERVICE, PACKET_CMD};
+use crate::{CMD_FLA_RD, CMD_FLA_WR, CMD_FPG_FLA, CMD_FPG_SDC, CMD_FPG_USB, CMD_F_FINFO, CMD_F_FOPN};
+use crate::{CMD_GET_MODE, CMD_HOST_RST, CMD_IO_RST, CMD_MEM_RD, CMD_MEM_WR, CMD_RUN_APP, CMD_STATUS, CMD_USB_RECOV};
+
+/// The size of the simulated flash.
+pub const FLASH_SIZE: usize = 0x200000;
+
+/// The largest FPGA core the simulator accepts. This is the simulator's
+/// limit, to bound its memory use, not one taken from the device.
+const FPGA_MAX_SIZE: usize = FLASH_SIZE;
+
+/// The byte sent in response to `CMD_GET_MODE` in app mode.
+const MODE_APP: u8 = 0xa2;
+
+/// How long the device waits for the rest of a command before giving up.
+const ARGUMENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A game started by the simulated menu.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RunningGame {
+    /// The path reported to the menu.
+    pub path: String,
+    /// The size of the ROM, in bytes.
+    pub size: u32,
+    /// Whether the menu was told to keep the current FPGA core.
+    pub skip_fpga: bool,
+}
+
+/// Why a session with the simulator ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionEnd {
+    /// The host closed the connection.
+    Closed,
+    /// The device changed mode, so it dropped off USB.
+    Reenumerated,
+}
+
+/// The state of the simulated menu.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum MenuState {
+    /// The Mega Drive is held in reset.
+    Reset,
+    /// The menu is running, and reading commands from the FIFO.
+    Running,
+    /// A game has been started.
+    Game,
+}
+
+struct State {
+    mode: Mode,
+    status: u8,
+    memory: Vec<Vec<u8>>,
+    flash: Vec<u8>,
+    flash_file: Option<PathBuf>,
+    sd_root: Option<PathBuf>,
+    current_file: Option<String>,
+    fpga: Option<FpgaSource>,
+    menu: MenuState,
+    fifo: Vec<u8>,
+    skip_fpga: bool,
+    game: Option<RunningGame>,
+    reenumerate_delay: Duration,
+    absent_until: Option<Instant>,
+}
+
+/// A simulated Mega Everdrive Pro.
+///
+/// This can be cloned, and all clones refer to the same device.
+#[derive(Clone)]
+pub struct Simulator {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for Simulator {
+    fn default() -> Simulator {
+        Simulator::new()
+    }
+}
+
+impl Simulator {
+    /// Create a new device in app mode, with blank memory and flash and no
+    /// SD card.
+    pub fn new() -> Simulator {
+        let memory = Region::ALL.iter()
+            .map(|r| vec![0u8; r.size().unwrap_or(0) as usize])
+            .collect();
+
+        Simulator {
+            state: Arc::new(Mutex::new(State {
+                mode: Mode::App,
+                status: 0,
+                memory,
+                flash: vec![0xff; FLASH_SIZE],
+                flash_file: None,
+                sd_root: None,
+                current_file: None,
+                fpga: None,
+                menu: MenuState::Running,
+                fifo: Vec::new(),
+                skip_fpga: false,
+                game: None,
+                reenumerate_delay: Duration::from_millis(100),
+                absent_until: None,
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Use a host directory as the SD card.
+    pub fn set_sd_root(&self, root: impl Into<PathBuf>) {
+        self.state().sd_root = Some(root.into());
+    }
+
+    /// Back the flash with a file. The flash is loaded from the file if it
+    /// exists, and the file is updated after each write.
+    pub fn set_flash_file(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        let mut state = self.state();
+        match fs::read(&path) {
+            Ok(data) => {
+                let n = data.len().min(FLASH_SIZE);
+                state.flash[..n].copy_from_slice(&data[..n]);
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+        state.flash_file = Some(path);
+        Ok(())
+    }
+
+    /// Set how long the device is missing from USB after changing mode.
+    pub fn set_reenumerate_delay(&self, delay: Duration) {
+        self.state().reenumerate_delay = delay;
+    }
+
+    /// Get a factory which connects to this device over in-memory pipes.
+    pub fn factory(&self) -> SimulatorFactory {
+        SimulatorFactory { sim: self.clone() }
+    }
+
+    /// Get the current mode.
+    pub fn mode(&self) -> Mode {
+        self.state().mode
+    }
+
+    /// Read from a memory region.
+    pub fn read_region(&self, region: Region, offset: u32, len: usize) -> Result<Vec<u8>> {
+        check_memory(region)?;
+        let addr = region.check(offset, len)? as usize - region.base() as usize;
+        Ok(self.state().memory[region_index(region)][addr..addr + len].to_vec())
+    }
+
+    /// Write to a memory region.
+    pub fn write_region(&self, region: Region, offset: u32, data: &[u8]) -> Result<()> {
+        check_memory(region)?;
+        let addr = region.check(offset, data.len())? as usize - region.base() as usize;
+        self.state().memory[region_index(region)][addr..addr + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Read from flash.
+    pub fn read_flash(&self, addr: u32, len: usize) -> Vec<u8> {
+        let state = self.state();
+        (addr as usize..addr as usize + len)
+            .map(|i| state.flash.get(i).copied().unwrap_or(0xff))
+            .collect()
+    }
+
+    /// Get the FPGA core which was loaded most recently.
+    pub fn fpga(&self) -> Option<FpgaSource> {
+        self.state().fpga.clone()
+    }
+
+    /// Get the game started by the menu, if there is one.
+    pub fn game(&self) -> Option<RunningGame> {
+        self.state().game.clone()
+    }
+
+    /// Handle commands from a transport, until the host closes it or the
+    /// device changes mode.
+    pub fn serve(&self, transport: &mut dyn Transport) -> Result<SessionEnd> {
+        Session { sim: self, t: transport }.run()
+    }
+
+    /// Returns true if the device is currently missing from USB.
+    fn is_absent(&self) -> bool {
+        let mut state = self.state();
+        match state.absent_until {
+            Some(t) if Instant::now() < t => true,
+            Some(_) => {
+                state.absent_until = None;
+                false
+            },
+            None => false,
+        }
+    }
+}
+
+/// Check that a region is memory the simulator holds, rather than the FIFO.
+fn check_memory(region: Region) -> Result<()> {
+    if region.size().is_none() {
+        let e = io::Error::new(ErrorKind::InvalidInput, format!("{} region is not memory", region.lower_name()));
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn region_index(region: Region) -> usize {
+    Region::ALL.iter().position(|r| *r == region).expect("region is in ALL")
+}
+
+/// A `SerialFactory` which connects to a `Simulator`, running the device on
+/// a background thread for each connection.
+pub struct SimulatorFactory {
+    sim: Simulator,
+}
+
+impl SerialFactory for SimulatorFactory {
+    type Transport = PipeTransport;
+
+    fn open(&mut self) -> Result<PipeTransport> {
+        if self.sim.is_absent() {
+            return Err(Error::other("simulated device is not connected"));
+        }
+
+        let (host, mut device) = pipe();
+        let sim = self.sim.clone();
+        thread::spawn(move || {
+            if let Err(e) = sim.serve(&mut device) {
+                warn!("simulator session failed: {}", e);
+            }
+        });
+        Ok(host)
+    }
+}
+
+struct Session<'a> {
+    sim: &'a Simulator,
+    t: &'a mut dyn Transport,
+}
+
+impl<'a> Session<'a> {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.sim.state()
+    }
+
+    fn run(&mut self) -> Result<SessionEnd> {
+        loop {
+            let cmd = match self.rx_cmd()? {
+                Some(cmd) => cmd,
+                None => return Ok(SessionEnd::Closed),
+            };
+
+            self.t.set_timeout(ARGUMENT_TIMEOUT)?;
+            match self.handle(cmd) {
+                Ok(Some(end)) => return Ok(end),
+                Ok(None) => {},
+                Err(e) if e.is_timeout() => debug!("sim: command {:02x} timed out", cmd),
+                Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    return Ok(SessionEnd::Closed);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Wait for a command header, skipping anything which is not one.
+    fn rx_cmd(&mut self) -> Result<Option<u8>> {
+        self.t.set_timeout(Duration::from_secs(3600))?;
+
+        let mut header = [0u8; 4];
+        let mut filled = 0;
+        loop {
+            match self.t.read(&mut header[filled..filled + 1]) {
+                Ok(0) => return Ok(None),
+                Ok(_) => filled += 1,
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+
+            let valid = match filled {
+                1 => header[0] == PACKET_CMD,
+                2 => header[1] == !PACKET_CMD,
+                3 => true,
+                _ => header[3] == !header[2],
+            };
+            if !valid {
+                // Start again, in case this byte begins the next header.
+                let last = header[filled - 1];
+                filled = 0;
+                if last == PACKET_CMD {
+                    header[0] = last;
+                    filled = 1;
+                }
+            } else if filled == 4 {
+                return Ok(Some(header[2]));
+            }
+        }
+    }
+
+    fn handle(&mut self, cmd: u8) -> Result<Option<SessionEnd>> {
+        debug!("sim: command {:02x}", cmd);
+        match cmd {
+            CMD_STATUS => {
+                let status = std::mem::take(&mut self.state().status);
+                self.tx_u16(0xa500 | status as u16)?;
+            },
+            CMD_GET_MODE => {
+                let mode = match self.state().mode {
+                    Mode::Service => MODE_SERVICE,
+                    Mode::App => MODE_APP,
+                };
+                self.tx(&[mode])?;
+            },
+            CMD_IO_RST => {
+                self.rx_u8()?;
+                return Ok(Some(self.reenumerate(Mode::Service)));
+            },
+            CMD_RUN_APP => {
+                return Ok(Some(self.reenumerate(Mode::App)));
+            },
+            CMD_HOST_RST => {
+                let mode = self.rx_u8()?;
+                self.host_reset(mode)?;
+            },
+            CMD_MEM_WR => {
+                let addr = self.rx_u32()?;
+                let len = self.rx_u32()? as usize;
+                self.rx_u8()?;
+                self.mem_write(addr, len)?;
+            },
+            CMD_MEM_RD => {
+                let addr = self.rx_u32()?;
+                let len = self.rx_u32()? as usize;
+                self.rx_u8()?;
+                self.mem_read(addr, len)?;
+            },
+            CMD_FLA_RD => {
+                let addr = self.rx_u32()?;
+                let len = self.rx_u32()? as usize;
+                for offset in (0..len).step_by(ACK_BLOCK_SIZE) {
+                    let n = (len - offset).min(ACK_BLOCK_SIZE);
+                    let data = self.sim.read_flash(addr.wrapping_add(offset as u32), n);
+                    self.tx(&data)?;
+                }
+            },
+            CMD_FLA_WR => {
+                let addr = self.rx_u32()? as usize;
+                let len = self.rx_u32()? as usize;
+                let limit = FLASH_SIZE.saturating_sub(addr);
+                match self.rx_ack(len, limit)? {
+                    Some(data) => self.flash_write(addr, &data)?,
+                    None => self.state().status = StatusCode::INVALID_PARAMETER.0,
+                }
+            },
+            CMD_FPG_USB => {
+                let len = self.rx_u32()? as usize;
+                match self.rx_ack(len, FPGA_MAX_SIZE)? {
+                    Some(data) => self.state().fpga = Some(FpgaSource::Data(data)),
+                    None => self.state().status = StatusCode::INVALID_PARAMETER.0,
+                }
+            },
+            CMD_FPG_SDC => {
+                let _size = self.rx_u32()?;
+                self.rx_u8()?;
+                let mut state = self.state();
+                match state.current_file.clone() {
+                    Some(path) => state.fpga = Some(FpgaSource::Sd(path)),
+                    None => state.status = StatusCode::INVALID_OBJECT.0,
+                }
+            },
+            CMD_FPG_FLA => {
+                let addr = self.rx_u32()?;
+                self.state().fpga = Some(FpgaSource::Flash(addr));
+            },
+            CMD_F_FINFO => {
+                let path = self.rx_str()?;
+                self.file_info(&path)?;
+            },
+            CMD_F_FOPN => {
+                let mode = self.rx_u8()?;
+                let path = self.rx_str()?;
+                self.file_open(&path, mode);
+            },
+            CMD_USB_RECOV => {
+                let _addr = self.rx_u32()?;
+                let _crc = self.rx_u32()?;
+                // The simulated core is never damaged.
+                self.state().status = StatusCode::CORE_MATCHES_RECOVERY.0;
+            },
+            other => {
+                warn!("sim: unsupported command {:02x}", other);
+            },
+        }
+        Ok(None)
+    }
+
+    fn reenumerate(&mut self, mode: Mode) -> SessionEnd {
+        debug!("sim: re-enumerating in {} mode", mode.lower_name());
+        let mut state = self.state();
+        state.mode = mode;
+        state.status = 0;
+        state.absent_until = Some(Instant::now() + state.reenumerate_delay);
+        SessionEnd::Reenumerated
+    }
+
+    fn host_reset(&mut self, mode: u8) -> Result<()> {
+        let boot = {
+            let mut state = self.state();
+            if mode == ResetMode::Off.command() {
+                let boot = state.menu == MenuState::Reset;
+                if boot {
+                    state.menu = MenuState::Running;
+                    state.fifo.clear();
+                    state.skip_fpga = false;
+                    state.game = None;
+                }
+                boot
+            } else {
+                state.menu = MenuState::Reset;
+                false
+            }
+        };
+
+        if boot {
+            debug!("sim: menu started");
+            self.tx(&[MENU_READY])?;
+        }
+        Ok(())
+    }
+
+    fn mem_write(&mut self, addr: u32, len: usize) -> Result<()> {
+        match Region::containing(addr, len) {
+            Some(Region::Fifo) => {
+                // The FIFO has no size, so pass it on a block at a time.
+                let mut block = vec![0u8; len.min(ACK_BLOCK_SIZE)];
+                let mut left = len;
+                while left > 0 {
+                    let n = left.min(block.len());
+                    self.t.read_exact(&mut block[..n])?;
+                    self.menu_input(&block[..n])?;
+                    left -= n;
+                }
+            },
+            Some(region) => {
+                // The length fits within the region, so it is bounded.
+                let mut data = vec![0u8; len];
+                self.t.read_exact(&mut data)?;
+                self.sim.write_region(region, addr - region.base(), &data)?;
+            },
+            None => {
+                warn!("sim: write of {} bytes to unmapped address {:x}", len, addr);
+                self.rx_discard(len)?;
+            },
+        }
+        Ok(())
+    }
+
+    fn mem_read(&mut self, addr: u32, len: usize) -> Result<()> {
+        match Region::containing(addr, len) {
+            Some(Region::Fifo) | None => {
+                let zeros = [0u8; ACK_BLOCK_SIZE];
+                let mut left = len;
+                while left > 0 {
+                    let n = left.min(zeros.len());
+                    self.tx(&zeros[..n])?;
+                    left -= n;
+                }
+                Ok(())
+            },
+            Some(region) => {
+                let data = self.sim.read_region(region, addr - region.base(), len)?;
+                self.tx(&data)
+            },
+        }
+    }
+
+    fn flash_write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        let mut state = self.state();
+        if addr + data.len() > state.flash.len() {
+            state.status = StatusCode::INVALID_PARAMETER.0;
+            return Ok(());
+        }
+
+        state.flash[addr..addr + data.len()].copy_from_slice(data);
+        if let Some(path) = state.flash_file.as_ref() {
+            fs::write(path, &state.flash)?;
+        }
+        Ok(())
+    }
+
+    /// Handle data written to the FIFO by the host.
+    fn menu_input(&mut self, data: &[u8]) -> Result<()> {
+        let mut state = self.state();
+        if state.menu != MenuState::Running {
+            debug!("sim: ignoring FIFO data, menu is not running");
+            return Ok(());
+        }
+
+        state.fifo.extend_from_slice(data);
+        let mut replies = Vec::new();
+        while let Some((cmd, n)) = MenuCommand::decode(&state.fifo)? {
+            state.fifo.drain(..n);
+            debug!("sim: menu command {:?}", cmd);
+
+            if let MenuCommand::StartGame { size, path } = &cmd {
+                let skip_fpga = state.skip_fpga;
+                if !path.starts_with("USB:") {
+                    let file = sd_path(state.sd_root.as_ref(), path)
+                        .and_then(|p| fs::read(p).ok());
+                    match file {
+                        Some(rom) => {
+                            let n = rom.len().min(state.memory[region_index(Region::Rom)].len());
+                            state.memory[region_index(Region::Rom)][..n].copy_from_slice(&rom[..n]);
+                        },
+                        None => warn!("sim: unable to load {} from the SD card", path),
+                    }
+                }
+
+                state.game = Some(RunningGame { path: path.clone(), size: *size, skip_fpga });
+                state.menu = MenuState::Game;
+            } else if cmd == MenuCommand::SkipFpga {
+                state.skip_fpga = true;
+            }
+
+            match cmd.expected_response() {
+                MenuResponse::Exact(v) => replies.push(v),
+                MenuResponse::Any => replies.push(0),
+                MenuResponse::None => {},
+            }
+
+            if state.menu != MenuState::Running {
+                break;
+            }
+        }
+
+        drop(state);
+        self.tx(&replies)
+    }
+
+    fn file_info(&mut self, path: &str) -> Result<()> {
+        let root = self.state().sd_root.clone();
+        let meta = match sd_path(root.as_ref(), path) {
+            Some(p) => fs::metadata(p).map_err(|e| fs_status(&e)),
+            None => Err(StatusCode::INVALID_NAME),
+        };
+
+        let meta = match meta {
+            Ok(m) => m,
+            Err(code) => return self.tx(&[code.0]),
+        };
+
+        self.state().current_file = Some(path.to_string());
+
+        let (date, time) = meta.modified().map(fat_time).unwrap_or((0, 0));
+        let name = path.rsplit('/').next().unwrap_or(path);
+        self.tx(&[0])?;
+        self.tx_u32(meta.len() as u32)?;
+        self.tx_u16(date)?;
+        self.tx_u16(time)?;
+        self.tx(&[if meta.is_dir() { 0x10 } else { 0x20 }])?;
+        self.tx_u16(name.len() as u16)?;
+        self.tx(name.as_bytes())
+    }
+
+    fn file_open(&mut self, path: &str, mode: u8) {
+        let mut state = self.state();
+        let status = match sd_path(state.sd_root.as_ref(), path) {
+            None => StatusCode::INVALID_NAME,
+            Some(p) if p.exists() => StatusCode::OK,
+            // Any of the create flags.
+            Some(p) if mode & 0x3c != 0 => match fs::write(&p, []) {
+                Ok(()) => StatusCode::OK,
+                Err(e) => fs_status(&e),
+            },
+            Some(p) if p.parent().is_some_and(|d| d.is_dir()) => StatusCode::NO_FILE,
+            Some(_) => StatusCode::NO_PATH,
+        };
+
+        if status.is_ok() {
+            state.current_file = Some(path.to_string());
+        }
+        state.status = status.0;
+    }
+
+    /// Receive data sent in acknowledged blocks. If there is more than
+    /// `limit` bytes, the data is still received to stay in step with the
+    /// host, but is thrown away.
+    fn rx_ack(&mut self, len: usize, limit: usize) -> Result<Option<Vec<u8>>> {
+        if len > limit {
+            warn!("sim: transfer of {} bytes is too large (the most is {})", len, limit);
+            let mut block = [0u8; ACK_BLOCK_SIZE];
+            let mut left = len;
+            while left > 0 {
+                let n = left.min(ACK_BLOCK_SIZE);
+                self.tx(&[0])?;
+                self.t.read_exact(&mut block[..n])?;
+                left -= n;
+            }
+            return Ok(None);
+        }
+
+        let mut data = vec![0u8; len];
+        for block in data.chunks_mut(ACK_BLOCK_SIZE) {
+            self.tx(&[0])?;
+            self.t.read_exact(block)?;
+        }
+        Ok(Some(data))
+    }
+
+    /// Receive and throw away data.
+    fn rx_discard(&mut self, len: usize) -> Result<()> {
+        let mut block = [0u8; ACK_BLOCK_SIZE];
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(ACK_BLOCK_SIZE);
+            self.t.read_exact(&mut block[..n])?;
+            left -= n;
+        }
+        Ok(())
+    }
+
+    fn tx(&mut self, data: &[u8]) -> Result<()> {
+        self.t.write_all(data)?;
+        self.t.flush()?;
+        Ok(())
+    }
+
+    fn tx_u16(&mut self, v: u16) -> Result<()> {
+        let mut buf = [0u8; 2];
+        BigEndian::write_u16(&mut buf, v);
+        self.tx(&buf)
+    }
+
+    fn tx_u32(&mut self, v: u32) -> Result<()> {
+        let mut buf = [0u8; 4];
+        BigEndian::write_u32(&mut buf, v);
+        self.tx(&buf)
+    }
+
+    fn rx_u8(&mut self) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.t.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn rx_u16(&mut self) -> Result<u16> {
+        let mut buf = [0u8; 2];
+        self.t.read_exact(&mut buf)?;
+        Ok(BigEndian::read_u16(&buf))
+    }
+
+    fn rx_u32(&mut self) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.t.read_exact(&mut buf)?;
+        Ok(BigEndian::read_u32(&buf))
+    }
+
+    fn rx_str(&mut self) -> Result<String> {
+        let len = self.rx_u16()? as usize;
+        let mut buf = vec![0u8; len];
+        self.t.read_exact(&mut buf)?;
+        String::from_utf8(buf)
+            .map_err(|e| Error::InvalidData(format!("invalid string: {}", e)))
+    }
+}
+
+/// Map a path on the simulated SD card to the host, refusing paths which
+/// leave the root.
+fn sd_path(root: Option<&PathBuf>, path: &str) -> Option<PathBuf> {
+    let root = root?;
+    let path = Path::new(path.trim_start_matches('/'));
+    if path.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(root.join(path))
+}
+
+fn fs_status(e: &io::Error) -> StatusCode {
+    match e.kind() {
+        ErrorKind::NotFound => StatusCode::NO_FILE,
+        ErrorKind::PermissionDenied => StatusCode::DENIED,
+        ErrorKind::AlreadyExists => StatusCode::EXISTS,
+        _ => StatusCode::DISK_ERROR,
+    }
+}
+
+/// Convert a time to the FAT date and time fields, in UTC.
+fn fat_time(t: SystemTime) -> (u16, u16) {
+    let secs = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => return (0, 0),
+    };
+
+    // Convert days since the epoch to a civil date.
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let date = (((year - 1980).clamp(0, 127) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let rem = secs % 86400;
+    let time = (((rem / 3600) as u16) << 11) | (((rem / 60 % 60) as u16) << 5) | (rem % 60 / 2) as u16;
+    (date, time)
+}
@@ -0,0 +1,130 @@
+//! Status codes returned by the device.
+//!
+//! Most codes are passed straight through from FatFs on the cartridge, so
+//! they are only meaningful after an SD card operation. The only other code
+//! known here is the recovery code `CORE_MATCHES_RECOVERY`: the codes used
+//! for flash and FPGA errors are not documented, so they are shown in hex,
+//! along with the operation which failed.
+
+use std::fmt;
+
+/// A status code returned by the device, where 0 indicates success.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct StatusCode(pub u8);
+
+impl StatusCode {
+    /// The operation succeeded.
+    pub const OK: StatusCode = StatusCode(0);
+    /// A low-level error occurred on the SD card.
+    pub const DISK_ERROR: StatusCode = StatusCode(1);
+    /// FatFs hit an internal assertion.
+    pub const INTERNAL_ERROR: StatusCode = StatusCode(2);
+    /// The SD card is not ready.
+    pub const NOT_READY: StatusCode = StatusCode(3);
+    /// The file could not be found.
+    pub const NO_FILE: StatusCode = StatusCode(4);
+    /// A directory in the path could not be found.
+    pub const NO_PATH: StatusCode = StatusCode(5);
+    /// The path is not valid.
+    pub const INVALID_NAME: StatusCode = StatusCode(6);
+    /// Access was denied, or the directory is full.
+    pub const DENIED: StatusCode = StatusCode(7);
+    /// The file already exists.
+    pub const EXISTS: StatusCode = StatusCode(8);
+    /// The file or directory object is not valid.
+    pub const INVALID_OBJECT: StatusCode = StatusCode(9);
+    /// The SD card is write-protected.
+    pub const WRITE_PROTECTED: StatusCode = StatusCode(10);
+    /// The drive number is not valid.
+    pub const INVALID_DRIVE: StatusCode = StatusCode(11);
+    /// The volume has no work area.
+    pub const NOT_ENABLED: StatusCode = StatusCode(12);
+    /// There is no valid FAT volume on the SD card.
+    pub const NO_FILESYSTEM: StatusCode = StatusCode(13);
+    /// Formatting was aborted.
+    pub const MKFS_ABORTED: StatusCode = StatusCode(14);
+    /// Access to the volume timed out.
+    pub const FS_TIMEOUT: StatusCode = StatusCode(15);
+    /// The file is locked by another operation.
+    pub const LOCKED: StatusCode = StatusCode(16);
+    /// FatFs could not allocate a working buffer.
+    pub const NOT_ENOUGH_CORE: StatusCode = StatusCode(17);
+    /// Too many files are open.
+    pub const TOO_MANY_OPEN_FILES: StatusCode = StatusCode(18);
+    /// A parameter was not valid.
+    pub const INVALID_PARAMETER: StatusCode = StatusCode(19);
+    /// Recovery was not needed, because the core in flash matches the
+    /// recovery copy.
+    pub const CORE_MATCHES_RECOVERY: StatusCode = StatusCode(0x88);
+
+    /// Returns true if the status indicates success.
+    pub fn is_ok(self) -> bool {
+        self == StatusCode::OK
+    }
+
+    /// Returns true if the status means that a file or directory does not
+    /// exist.
+    pub fn is_not_found(self) -> bool {
+        matches!(self, StatusCode::NO_FILE | StatusCode::NO_PATH)
+    }
+
+    /// Get a description of the status, if it is a known status.
+    pub fn description(self) -> Option<&'static str> {
+        STATUS_CODES.iter()
+            .find(|(code, _)| *code == self)
+            .map(|(_, desc)| *desc)
+    }
+}
+
+impl From<u8> for StatusCode {
+    fn from(code: u8) -> StatusCode {
+        StatusCode(code)
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.description() {
+            Some(desc) => write!(f, "{} (status {})", desc, self.0),
+            None => write!(f, "status {:#04x}", self.0),
+        }
+    }
+}
+
+const STATUS_CODES: &[(StatusCode, &str)] = &[
+    (StatusCode::OK, "success"),
+    (StatusCode::DISK_ERROR, "SD card I/O error"),
+    (StatusCode::INTERNAL_ERROR, "internal file system error"),
+    (StatusCode::NOT_READY, "SD card not ready"),
+    (StatusCode::NO_FILE, "file not found"),
+    (StatusCode::NO_PATH, "directory not found"),
+    (StatusCode::INVALID_NAME, "invalid path"),
+    (StatusCode::DENIED, "access denied or directory full"),
+    (StatusCode::EXISTS, "file already exists"),
+    (StatusCode::INVALID_OBJECT, "invalid file object"),
+    (StatusCode::WRITE_PROTECTED, "SD card is write-protected"),
+    (StatusCode::INVALID_DRIVE, "invalid drive"),
+    (StatusCode::NOT_ENABLED, "volume not mounted"),
+    (StatusCode::NO_FILESYSTEM, "no FAT file system on SD card"),
+    (StatusCode::MKFS_ABORTED, "format aborted"),
+    (StatusCode::FS_TIMEOUT, "timed out accessing SD card"),
+    (StatusCode::LOCKED, "file is locked"),
+    (StatusCode::NOT_ENOUGH_CORE, "not enough memory for file system"),
+    (StatusCode::TOO_MANY_OPEN_FILES, "too many open files"),
+    (StatusCode::INVALID_PARAMETER, "invalid parameter"),
+    (StatusCode::CORE_MATCHES_RECOVERY, "core matches recovery copy"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!(StatusCode::NO_FILE.to_string(), "file not found (status 4)");
+        assert_eq!(StatusCode::CORE_MATCHES_RECOVERY.to_string(), "core matches recovery copy (status 136)");
+        assert_eq!(StatusCode(0x41).to_string(), "status 0x41");
+        assert!(StatusCode::NO_PATH.is_not_found());
+        assert!(!StatusCode(0x41).is_ok());
+    }
+}
@@ -0,0 +1,235 @@
+//! The byte streams used to talk to the device.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+#[cfg(feature = "serial")]
+use serialport::SerialPort;
+use crate::Result;
+
+/// A connection to the device.
+///
+/// Reads should fail with `io::ErrorKind::TimedOut` if no data arrives
+/// within the timeout, and return 0 once the connection is closed.
+pub trait Transport: Read + Write {
+    /// Get the read timeout.
+    fn timeout(&self) -> Duration;
+
+    /// Set the read timeout.
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()>;
+
+    /// Discard any data waiting to be read, waiting up to `timeout` for more
+    /// to arrive.
+    fn drain(&mut self, timeout: Duration) -> Result<()> {
+        let old_timeout = self.timeout();
+        self.set_timeout(timeout)?;
+
+        let mut tmp = [0u8; 1024];
+        while let Ok(n) = self.read(&mut tmp) {
+            if n == 0 {
+                break;
+            }
+        }
+
+        self.set_timeout(old_timeout)
+    }
+
+    /// Called before the header of each command is written, so that
+    /// wrappers such as `capture::Recorder` can mark where commands start.
+    /// This does nothing by default.
+    fn mark_command(&mut self, _cmd: u8) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn timeout(&self) -> Duration {
+        self.as_ref().timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.as_mut().set_timeout(timeout)
+    }
+
+    fn drain(&mut self, timeout: Duration) -> Result<()> {
+        self.as_mut().drain(timeout)
+    }
+
+    fn mark_command(&mut self, cmd: u8) -> Result<()> {
+        self.as_mut().mark_command(cmd)
+    }
+}
+
+#[cfg(feature = "serial")]
+impl Transport for dyn SerialPort {
+    fn timeout(&self) -> Duration {
+        SerialPort::timeout(self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        SerialPort::set_timeout(self, timeout)?;
+        Ok(())
+    }
+}
+
+/// A transport over a TCP connection, such as a serial bridge.
+pub struct TcpTransport {
+    stream: TcpStream,
+    timeout: Duration,
+}
+
+impl TcpTransport {
+    /// Wrap a connected stream.
+    pub fn new(stream: TcpStream) -> Result<TcpTransport> {
+        let mut t = TcpTransport { stream, timeout: Duration::from_secs(1) };
+        t.stream.set_nodelay(true)?;
+        t.set_timeout(t.timeout)?;
+        Ok(t)
+    }
+
+    /// Connect to `addr`, which is in the form `host:port`.
+    pub fn connect(addr: &str) -> Result<TcpTransport> {
+        TcpTransport::new(TcpStream::connect(addr)?)
+    }
+
+    /// Get the underlying stream.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Sockets report read timeouts as `WouldBlock` on some platforms.
+        self.stream.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, e),
+            _ => e,
+        })
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        // A zero timeout would mean blocking forever.
+        self.stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct PipeBuffer {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct PipeChannel {
+    buffer: Mutex<PipeBuffer>,
+    ready: Condvar,
+}
+
+impl PipeChannel {
+    fn close(&self) {
+        self.buffer.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// One end of an in-memory pipe, created with `pipe`.
+pub struct PipeTransport {
+    rx: Arc<PipeChannel>,
+    tx: Arc<PipeChannel>,
+    timeout: Duration,
+}
+
+/// Create a pair of connected in-memory transports. Data written to one
+/// can be read from the other.
+pub fn pipe() -> (PipeTransport, PipeTransport) {
+    let a = Arc::new(PipeChannel::default());
+    let b = Arc::new(PipeChannel::default());
+    let timeout = Duration::from_secs(1);
+    (
+        PipeTransport { rx: a.clone(), tx: b.clone(), timeout },
+        PipeTransport { rx: b, tx: a, timeout },
+    )
+}
+
+impl Read for PipeTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let mut buffer = self.rx.buffer.lock().unwrap();
+        while buffer.data.is_empty() {
+            if buffer.closed {
+                return Ok(0);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "pipe read timed out"));
+            }
+            buffer = self.rx.ready.wait_timeout(buffer, deadline - now).unwrap().0;
+        }
+
+        let n = buf.len().min(buffer.data.len());
+        for (dst, src) in buf.iter_mut().zip(buffer.data.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for PipeTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buffer = self.tx.buffer.lock().unwrap();
+        if buffer.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed"));
+        }
+
+        buffer.data.extend(buf);
+        self.tx.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for PipeTransport {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+impl Drop for PipeTransport {
+    fn drop(&mut self) {
+        self.rx.close();
+        self.tx.close();
+    }
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use std::fs;
+use std::process::Command;
+use megalink_rs::capture::{parse_capture, Event};
+use megalink_rs::decode::{decode_capture, Frame};
+use common::pattern;
+
+/// A capture of `megalink --simulate DIR --capture load-game.cap run test.bin`,
+/// where test.bin holds `pattern(2048)`.
+const LOAD_GAME: &str = include_str!("data/load-game.cap");
+
+#[test]
+fn commands_match_decoder() {
+    let records = parse_capture(LOAD_GAME).unwrap();
+    let marked: Vec<u8> = records.iter()
+        .filter_map(|r| match r.event {
+            Event::Command(cmd) => Some(cmd),
+            _ => None,
+        })
+        .collect();
+    let decoded: Vec<u8> = decode_capture(&records).into_iter()
+        .filter_map(|(_, f)| match f {
+            Frame::Command(cmd) => Some(cmd.code()),
+            _ => None,
+        })
+        .collect();
+
+    assert!(!marked.is_empty());
+    assert_eq!(marked, decoded);
+}
+
+#[test]
+fn replay_load_game() {
+    let dir = std::env::temp_dir().join(format!("megalink-test-replay-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let rom = dir.join("test.bin");
+    fs::write(&rom, pattern(2048)).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_megalink"))
+        .arg("--config").arg("/dev/null")
+        .arg("--replay").arg(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/load-game.cap"))
+        .arg("run").arg(&rom)
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
@@ -0,0 +1,27 @@
+//! Helpers shared by the integration tests.
+
+// Each test crate only uses some of these.
+#![allow(dead_code)]
+
+use std::time::Duration;
+use megalink_rs::sim::{Simulator, SimulatorFactory};
+use megalink_rs::EverdriveSerial;
+
+/// Make test data which differs from byte to byte and from block to block.
+pub fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 + i / 256) as u8).collect()
+}
+
+/// Create a simulated device which comes back quickly after changing mode.
+pub fn simulator() -> Simulator {
+    let sim = Simulator::new();
+    sim.set_reenumerate_delay(Duration::from_millis(10));
+    sim
+}
+
+/// Connect to a new simulated device.
+pub fn connect() -> (Simulator, EverdriveSerial<SimulatorFactory>) {
+    let sim = simulator();
+    let device = EverdriveSerial::builder(sim.factory()).build().unwrap();
+    (sim, device)
+}
@@ -0,0 +1,170 @@
+mod common;
+
+use std::cell::RefCell;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+use megalink_rs::sim::{RunningGame, SimulatorFactory};
+use megalink_rs::{EverdriveSerial, MenuCommand, Phase, ProgressEvent, Region};
+use common::{connect, pattern};
+
+/// Read memory through the device, so that any writes it has sent are
+/// handled by the simulator first.
+fn read(device: &mut EverdriveSerial<SimulatorFactory>, region: Region, offset: u32, len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    device.read_region(region, offset, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn write_across_chunks() {
+    let (_sim, mut device) = connect();
+    let data = pattern(1000);
+
+    let mut cursor = device.cursor(Region::Sram);
+    cursor.set_chunk_size(64);
+    cursor.seek(SeekFrom::Start(30)).unwrap();
+    for piece in data.chunks(47) {
+        cursor.write_all(piece).unwrap();
+    }
+    cursor.flush().unwrap();
+    drop(cursor);
+
+    assert!(read(&mut device, Region::Sram, 30, data.len()) == data);
+    assert_eq!(read(&mut device, Region::Sram, 0, 30), [0; 30]);
+}
+
+#[test]
+fn read_across_chunks() {
+    let (sim, mut device) = connect();
+    let data = pattern(1000);
+    sim.write_region(Region::Bram, 100, &data).unwrap();
+
+    let mut cursor = device.cursor(Region::Bram);
+    cursor.set_chunk_size(100);
+    cursor.seek(SeekFrom::Start(100)).unwrap();
+    let mut buf = vec![0; data.len()];
+    cursor.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    cursor.seek(SeekFrom::Current(-500)).unwrap();
+    let mut buf = vec![0; 250];
+    cursor.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, &data[500..750]);
+}
+
+#[test]
+fn read_to_end_of_region() {
+    let (sim, mut device) = connect();
+    let size = Region::Sram.size().unwrap() as usize;
+    sim.write_region(Region::Sram, (size - 3) as u32, &[1, 2, 3]).unwrap();
+
+    let mut cursor = device.cursor(Region::Sram);
+    assert_eq!(cursor.seek(SeekFrom::End(-10)).unwrap(), size as u64 - 10);
+    let mut buf = Vec::new();
+    cursor.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, [0, 0, 0, 0, 0, 0, 0, 1, 2, 3]);
+
+    // Writes past the end of the region are cut short.
+    cursor.seek(SeekFrom::End(-2)).unwrap();
+    assert_eq!(cursor.write(&[9, 9, 9, 9]).unwrap(), 2);
+    assert_eq!(cursor.write(&[9]).unwrap(), 0);
+}
+
+#[test]
+fn write_buffered_across_seek() {
+    let (_sim, mut device) = connect();
+
+    let mut cursor = device.cursor(Region::Sram);
+    cursor.write_all(&[1, 2, 3]).unwrap();
+    // Seeking flushes the buffered data to where it was written.
+    cursor.seek(SeekFrom::Start(10)).unwrap();
+    cursor.write_all(&[4, 5]).unwrap();
+
+    let mut buf = [0; 2];
+    cursor.seek(SeekFrom::Start(1)).unwrap();
+    cursor.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [2, 3]);
+    drop(cursor);
+
+    assert_eq!(read(&mut device, Region::Sram, 0, 12), [1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 4, 5]);
+}
+
+#[test]
+fn shrink_chunk_size() {
+    let (_sim, mut device) = connect();
+    let data = pattern(300);
+
+    let mut cursor = device.cursor(Region::Sram);
+    cursor.set_chunk_size(256);
+    cursor.write_all(&data[..200]).unwrap();
+    cursor.set_chunk_size(16);
+    cursor.write_all(&data[200..]).unwrap();
+    cursor.flush().unwrap();
+    drop(cursor);
+
+    assert!(read(&mut device, Region::Sram, 0, data.len()) == data);
+}
+
+#[test]
+fn invalid_seeks() {
+    let (_sim, mut device) = connect();
+
+    let mut cursor = device.cursor(Region::Sram);
+    assert!(cursor.seek(SeekFrom::Start(u64::MAX)).is_err());
+    assert!(cursor.seek(SeekFrom::Current(-1)).is_err());
+    assert!(cursor.seek(SeekFrom::End(i64::MAX)).is_err());
+    assert_eq!(cursor.stream_position().unwrap(), 0);
+    drop(cursor);
+
+    let mut fifo = device.cursor(Region::Fifo);
+    assert!(fifo.seek(SeekFrom::End(0)).is_err());
+    assert!(fifo.seek(SeekFrom::Start(0)).is_err());
+}
+
+#[test]
+fn fifo_writes_to_port() {
+    let (sim, mut device) = connect();
+
+    // Each chunk goes to the FIFO's port address, which is the only address
+    // in the region, so the menu sees the command whole.
+    let cmd = MenuCommand::StartGame { size: 0x100, path: "USB:test.bin".to_string() };
+    let mut fifo = device.cursor(Region::Fifo);
+    fifo.set_chunk_size(4);
+    fifo.write_all(&cmd.encode().unwrap()).unwrap();
+    fifo.flush().unwrap();
+    drop(fifo);
+
+    let mut resp = [0; 1];
+    device.fifo_read(&mut resp).unwrap();
+    assert_eq!(sim.game(), Some(RunningGame {
+        path: "USB:test.bin".to_string(),
+        size: 0x100,
+        skip_fpga: false,
+    }));
+}
+
+#[test]
+fn progress() {
+    let (_sim, mut device) = connect();
+    let events = Rc::new(RefCell::new(Vec::new()));
+
+    let mut cursor = device.cursor(Region::Sram);
+    cursor.set_chunk_size(100);
+    let log = events.clone();
+    cursor.set_progress(Some(Box::new(move |e: &ProgressEvent| log.borrow_mut().push((e.phase, e.done, e.total)))), 250);
+
+    cursor.write_all(&pattern(250)).unwrap();
+    cursor.flush().unwrap();
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut buf = vec![0; 150];
+    cursor.read_exact(&mut buf).unwrap();
+    drop(cursor);
+
+    assert_eq!(*events.borrow(), [
+        (Phase::Upload, 100, 250),
+        (Phase::Upload, 200, 250),
+        (Phase::Upload, 250, 250),
+        (Phase::Download, 100, 250),
+        (Phase::Download, 150, 250),
+    ]);
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use std::time::Duration;
+use megalink_rs::fault::{FaultFactory, FaultInjector, Faults, ModeChangeFault};
+use megalink_rs::sim::{Simulator, SimulatorFactory};
+use megalink_rs::{EverdriveSerial, Error, Mode, ReconnectPolicy, Region};
+use common::pattern;
+
+type Device = EverdriveSerial<FaultFactory<SimulatorFactory>>;
+
+fn connect(faults: Faults, retries: usize) -> (Simulator, Device) {
+    let sim = common::simulator();
+    let factory = FaultFactory::new(sim.factory(), FaultInjector::new(faults));
+    let device = EverdriveSerial::builder(factory)
+        .command_timeout(Duration::from_millis(50))
+        .drain_timeout(Duration::from_millis(20))
+        .retries(retries)
+        .reconnect_policy(ReconnectPolicy {
+            timeout: Duration::from_millis(500),
+            disconnect_timeout: Duration::from_millis(100),
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+        })
+        .probe(false)
+        .build()
+        .unwrap();
+    (sim, device)
+}
+
+/// Read back repeatedly over a link which loses or damages data, which only
+/// works if the host re-synchronises and retries.
+fn read_through_faults(faults: Faults) {
+    let (sim, mut device) = connect(faults, 20);
+    let data = pattern(64);
+    sim.write_region(Region::Sram, 0, &data).unwrap();
+
+    for _ in 0..20 {
+        assert_eq!(device.get_mode().unwrap(), Mode::App);
+    }
+
+    // Bulk reads are not retried, but the session must be usable after a
+    // failed one.
+    let mut good = 0;
+    for _ in 0..20 {
+        let mut buf = vec![0; data.len()];
+        match device.read_region(Region::Sram, 0, &mut buf) {
+            Ok(()) if buf == data => good += 1,
+            Ok(()) => {},
+            Err(e) => assert!(e.is_desync(), "{}", e),
+        }
+    }
+    assert!(good > 0);
+    assert_eq!(device.get_mode().unwrap(), Mode::App);
+}
+
+#[test]
+fn drop_resyncs() {
+    read_through_faults(Faults { seed: 1, drop: 0.01, ..Faults::default() });
+}
+
+#[test]
+fn corrupt_resyncs() {
+    read_through_faults(Faults { seed: 2, corrupt: 0.01, ..Faults::default() });
+}
+
+#[test]
+fn timeouts_retry() {
+    let (_sim, mut device) = connect(Faults { timeout_every: 7, ..Faults::default() }, 5);
+    for _ in 0..10 {
+        assert_eq!(device.get_mode().unwrap(), Mode::App);
+    }
+}
+
+#[test]
+fn timeouts_without_retries_fail() {
+    let (_sim, mut device) = connect(Faults { timeout_every: 7, ..Faults::default() }, 0);
+    let mut failed = 0;
+    for _ in 0..10 {
+        match device.get_mode() {
+            Ok(mode) => assert_eq!(mode, Mode::App),
+            Err(e) => {
+                assert!(e.is_timeout(), "{}", e);
+                failed += 1;
+            },
+        }
+    }
+    assert!(failed > 0);
+}
+
+#[test]
+fn resync_gives_up() {
+    let (_sim, mut device) = connect(Faults { timeout_every: 1, ..Faults::default() }, 5);
+    assert!(matches!(device.get_mode(), Err(Error::Resync)));
+}
+
+fn mode_change_fails(fault: ModeChangeFault) -> Error {
+    let (_sim, mut device) = connect(Faults { mode_change: fault, ..Faults::default() }, 1);
+    let e = device.set_mode(Mode::Service).unwrap_err();
+    assert!(matches!(e, Error::Reconnect { .. }), "{}", e);
+    e
+}
+
+#[test]
+fn lost_mode_change() {
+    match mode_change_fails(ModeChangeFault::Lose) {
+        Error::Reconnect { disconnected, last_error, .. } => {
+            assert!(!disconnected);
+            assert!(matches!(last_error.as_deref(), Some(Error::WrongMode { actual: Mode::App, .. })));
+        },
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn disconnect_on_mode_change() {
+    match mode_change_fails(ModeChangeFault::Disconnect) {
+        Error::Reconnect { disconnected, last_error, .. } => {
+            assert!(disconnected);
+            assert!(matches!(last_error.as_deref(), Some(Error::WrongMode { actual: Mode::App, .. })));
+        },
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn failed_opens_after_mode_change() {
+    match mode_change_fails(ModeChangeFault::FailOpens(1000)) {
+        Error::Reconnect { disconnected, last_error, .. } => {
+            assert!(disconnected);
+            assert!(matches!(last_error.as_deref(), Some(Error::Io(_))));
+        },
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn mode_change_survives_failed_opens() {
+    let (sim, mut device) = connect(Faults { mode_change: ModeChangeFault::FailOpens(2), ..Faults::default() }, 1);
+    device.set_mode(Mode::Service).unwrap();
+    assert_eq!(sim.mode(), Mode::Service);
+}
+
+#[test]
+fn payload_is_not_a_mode_change() {
+    // A flash write whose data holds an IoReset header must not set off the
+    // mode change fault.
+    let (sim, mut device) = connect(Faults { mode_change: ModeChangeFault::Disconnect, ..Faults::default() }, 1);
+    let data = [0x2b, 0xd4, 0x12, 0xed, 0x00];
+    device.write_flash(0, &data).unwrap();
+    assert_eq!(sim.read_flash(0, data.len()), data);
+    assert_eq!(device.get_mode().unwrap(), Mode::App);
+}
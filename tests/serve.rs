@@ -0,0 +1,124 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+use megalink_rs::serve::Server;
+use megalink_rs::sim::{RunningGame, Simulator};
+use megalink_rs::{EverdriveSerial, GameInfo, Mode, ReconnectPolicy, SerialFactory, TcpFactory, Transport};
+use common::pattern;
+
+/// Serve a simulated device on a local port, and return its address.
+fn serve(sim: &Simulator, hold: Duration) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let mut server = Server::new(sim.factory());
+    server.set_hold(hold);
+    thread::spawn(move || server.run(listener));
+    addr
+}
+
+fn connect(addr: &str) -> EverdriveSerial<TcpFactory> {
+    EverdriveSerial::builder(TcpFactory::new(addr))
+        .reconnect_policy(ReconnectPolicy {
+            timeout: Duration::from_secs(5),
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            ..ReconnectPolicy::default()
+        })
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn load_game() {
+    let sim = common::simulator();
+    let addr = serve(&sim, Duration::from_secs(10));
+    let mut device = connect(&addr);
+
+    let rom = pattern(3000);
+    device.load_game(&GameInfo::usb("test.bin"), &rom).unwrap();
+    assert_eq!(sim.game(), Some(RunningGame {
+        path: "USB:test.bin".to_string(),
+        size: rom.len() as u32,
+        skip_fpga: false,
+    }));
+}
+
+#[test]
+fn reconnect_after_mode_change() {
+    let sim = common::simulator();
+    let addr = serve(&sim, Duration::from_secs(10));
+    let mut device = connect(&addr);
+
+    device.set_mode(Mode::Service).unwrap();
+    assert_eq!(device.get_mode().unwrap(), Mode::Service);
+    device.set_mode(Mode::App).unwrap();
+    assert_eq!(sim.mode(), Mode::App);
+}
+
+#[test]
+fn one_client_at_a_time() {
+    let sim = common::simulator();
+    let addr = serve(&sim, Duration::from_secs(10));
+    let device = connect(&addr);
+
+    let e = TcpFactory::new(addr.as_str()).open().err().unwrap();
+    assert!(e.to_string().contains("in use"), "{}", e);
+
+    // Closing the connection releases the device straight away.
+    drop(device);
+    let mut device = connect(&addr);
+    assert_eq!(device.get_mode().unwrap(), Mode::App);
+}
+
+/// Ask the device to change to service mode, and wait for the server to
+/// close the connection as the device goes away, without reconnecting.
+fn change_mode_and_leave(addr: &str) {
+    let mut t = TcpFactory::new(addr).open().unwrap();
+    t.write_all(&[0x2b, 0xd4, 0x12, 0xed, 0x00]).unwrap();
+    t.set_timeout(Duration::from_secs(5)).unwrap();
+    let mut buf = [0u8; 16];
+    while t.read(&mut buf).unwrap() > 0 {}
+}
+
+#[test]
+fn held_while_changing_mode() {
+    let sim = common::simulator();
+    let addr = serve(&sim, Duration::from_secs(10));
+    change_mode_and_leave(&addr);
+
+    thread::sleep(Duration::from_millis(100));
+    let e = TcpFactory::new(addr.as_str()).open().err().unwrap();
+    assert!(e.to_string().contains("in use"), "{}", e);
+}
+
+#[test]
+fn released_after_hold() {
+    let sim = common::simulator();
+    let addr = serve(&sim, Duration::from_millis(0));
+    change_mode_and_leave(&addr);
+
+    thread::sleep(Duration::from_millis(100));
+    let mut device = connect(&addr);
+    assert_eq!(device.get_mode().unwrap(), Mode::Service);
+}
+
+#[test]
+fn device_not_connected() {
+    let sim = common::simulator();
+    sim.set_reenumerate_delay(Duration::from_secs(60));
+    let addr = serve(&sim, Duration::from_secs(10));
+
+    // Changing mode directly leaves the device missing from USB.
+    let mut factory = TcpFactory::new(addr.as_str());
+    let mut device = EverdriveSerial::builder(sim.factory())
+        .reconnect_policy(ReconnectPolicy { timeout: Duration::from_millis(100), ..ReconnectPolicy::default() })
+        .build()
+        .unwrap();
+    assert!(device.set_mode(Mode::Service).is_err());
+
+    let e = factory.open().err().unwrap();
+    assert!(e.to_string().contains("not connected"), "{}", e);
+}
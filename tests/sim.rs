@@ -0,0 +1,130 @@
+mod common;
+
+use std::cell::RefCell;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use megalink_rs::sim::RunningGame;
+use megalink_rs::{GameInfo, Mode, Phase, ProgressEvent, Region};
+use common::{connect, pattern};
+
+/// Create an empty directory to use as the SD card.
+fn sd_root(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("megalink-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn load_game() {
+    let (sim, mut device) = connect();
+    let rom = pattern(5000);
+
+    device.load_game(&GameInfo::usb("test.bin"), &rom).unwrap();
+
+    assert_eq!(sim.game(), Some(RunningGame {
+        path: "USB:test.bin".to_string(),
+        size: rom.len() as u32,
+        skip_fpga: false,
+    }));
+    assert!(sim.read_region(Region::Rom, 0, rom.len()).unwrap() == rom);
+}
+
+#[test]
+fn load_game_progress() {
+    let (_sim, mut device) = connect();
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let log = events.clone();
+    device.set_progress(Some(Box::new(move |e: &ProgressEvent| log.borrow_mut().push((e.phase, e.done, e.total)))));
+
+    device.load_game(&GameInfo::usb("test.bin"), &pattern(5000)).unwrap();
+
+    let events = events.borrow();
+    assert_eq!(events.first(), Some(&(Phase::Upload, 5000, 5000)));
+    assert_eq!(events.last(), Some(&(Phase::Boot, 1, 1)));
+}
+
+#[test]
+fn flash_round_trip() {
+    let (sim, mut device) = connect();
+    let data = pattern(3000);
+
+    device.write_flash(0x1000, &data).unwrap();
+    assert!(sim.read_flash(0x1000, data.len()) == data);
+
+    let mut buf = vec![0; data.len() + 16];
+    device.read_flash(0x1000 - 8, &mut buf).unwrap();
+    assert_eq!(&buf[..8], [0xff; 8]);
+    assert!(buf[8..8 + data.len()] == data[..]);
+    assert_eq!(&buf[8 + data.len()..], [0xff; 8]);
+}
+
+#[test]
+fn flash_write_past_end() {
+    let (_sim, mut device) = connect();
+
+    let end = megalink_rs::sim::FLASH_SIZE as u32;
+    assert!(device.write_flash(end - 16, &[0; 32]).is_err());
+
+    // The device should still be in step after refusing the write.
+    assert_eq!(device.get_mode().unwrap(), Mode::App);
+}
+
+#[test]
+fn set_mode_reenumerates() {
+    let (sim, mut device) = connect();
+
+    device.set_mode(Mode::Service).unwrap();
+    assert_eq!(sim.mode(), Mode::Service);
+    assert_eq!(device.get_mode().unwrap(), Mode::Service);
+
+    device.set_mode(Mode::App).unwrap();
+    assert_eq!(sim.mode(), Mode::App);
+    assert_eq!(device.get_mode().unwrap(), Mode::App);
+}
+
+#[test]
+fn sd_file_info() {
+    let root = sd_root("file-info");
+    fs::create_dir(root.join("games")).unwrap();
+    fs::write(root.join("games/sonic.md"), pattern(1234)).unwrap();
+
+    let (sim, mut device) = connect();
+    sim.set_sd_root(&root);
+
+    let meta = device.get_file_metadata("games/sonic.md").unwrap();
+    assert_eq!(meta.name, "sonic.md");
+    assert_eq!(meta.size, 1234);
+    assert_eq!(meta.attrib & 0x10, 0);
+
+    let e = device.get_file_metadata("games/missing.md").err().unwrap();
+    assert!(e.is_not_found(), "{}", e);
+
+    // A failed lookup should not leave the device out of step.
+    let meta = device.get_file_metadata("games").unwrap();
+    assert_eq!(meta.attrib & 0x10, 0x10);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn memory_bounds() {
+    let (sim, mut device) = connect();
+
+    assert_eq!(Region::containing(0x1810000, 16), Some(Region::Fifo));
+    assert_eq!(Region::containing(0x1810010, 16), None);
+    assert_eq!(Region::containing(0x2000000, 0x100000), None);
+    assert_eq!(Region::containing(0xffffff00, 16), None);
+    assert!(Region::Fifo.check(0xffff0000, 1).is_err());
+    assert!(Region::Sram.check(u32::MAX, 1).is_err());
+
+    assert!(device.write_memory(0x3000000, &[1, 2, 3]).is_err());
+    let mut buf = [0; 3];
+    assert!(device.read_memory(0x1810010, &mut buf).is_err());
+
+    device.write_memory(0x1000010, &[1, 2, 3]).unwrap();
+    device.read_memory(0x1000010, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3]);
+    assert_eq!(sim.read_region(Region::Sram, 0x10, 3).unwrap(), [1, 2, 3]);
+}